@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An IPv4 or IPv6 network such as `10.0.0.0/8` or `2001:db8::/32`. A bare address
+/// is a network of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpCidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpCidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_eq(a: &[u8], b: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = (prefix_len / 8) as usize;
+    let rest_bits = prefix_len % 8;
+    if a[..full_bytes] != b[..full_bytes] {
+        return false;
+    }
+    if rest_bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - rest_bits);
+    a[full_bytes] & mask == b[full_bytes] & mask
+}
+
+impl FromStr for IpCidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.trim().split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s.trim(), None),
+        };
+        let network = IpAddr::from_str(addr)
+            .map_err(|_| format!("Invalid address in {}", s))?
+            .to_canonical();
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse::<u8>()
+                .ok()
+                .filter(|l| *l <= max_len)
+                .ok_or_else(|| format!("Invalid prefix length in {}", s))?,
+            None => max_len,
+        };
+        Ok(IpCidr { network, prefix_len })
+    }
+}
+
+fn is_trusted(ip: &IpAddr, trusted: &[IpCidr]) -> bool {
+    trusted.iter().any(|c| c.contains(ip))
+}
+
+/// Node from a `Forwarded` `for=` parameter: `192.0.2.1`, `"[2001:db8::1]:443"`
+/// or `"192.0.2.1:443"`. Obfuscated and `unknown` nodes yield `None`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']').and_then(|(ip, _)| ip.parse().ok());
+    }
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    node.rsplit_once(':').and_then(|(ip, _)| ip.parse().ok())
+}
+
+fn forwarded_chain(headers: &HashMap<String, String>) -> Option<Vec<Option<IpAddr>>> {
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+    };
+    if let Some(forwarded) = header("Forwarded") {
+        let chain = forwarded
+            .split(',')
+            .filter_map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (k, v) = pair.split_once('=')?;
+                    k.trim().eq_ignore_ascii_case("for").then(|| parse_node(v))
+                })
+            })
+            .collect();
+        return Some(chain);
+    }
+    header("X-Forwarded-For").map(|xff| xff.split(',').map(parse_node).collect())
+}
+
+/// The address of the original client. Forwarding headers are only believed when
+/// `peer` is a trusted proxy; the chain is then walked from the nearest hop back,
+/// skipping further trusted proxies, so clients cannot spoof their address by
+/// prepending entries.
+pub fn resolve_client_ip(
+    peer: IpAddr,
+    headers: &HashMap<String, String>,
+    trusted: &[IpCidr],
+) -> IpAddr {
+    if !is_trusted(&peer, trusted) {
+        return peer;
+    }
+    let Some(chain) = forwarded_chain(headers) else {
+        return peer;
+    };
+    let mut client = peer;
+    for hop in chain.into_iter().rev() {
+        match hop {
+            Some(ip) => {
+                client = ip;
+                if !is_trusted(&ip, trusted) {
+                    break;
+                }
+            }
+            None => break,
+        }
+    }
+    client
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(name: &str, value: &str) -> HashMap<String, String> {
+        HashMap::from([(name.to_string(), value.to_string())])
+    }
+
+    #[test]
+    fn cidr_should_match_networks() {
+        let v4: IpCidr = "10.1.0.0/16".parse().unwrap();
+        assert!(v4.contains(&"10.1.200.3".parse().unwrap()));
+        assert!(!v4.contains(&"10.2.0.1".parse().unwrap()));
+        assert!(v4.contains(&"::ffff:10.1.0.9".parse().unwrap()));
+
+        let v6: IpCidr = "2001:db8::/33".parse().unwrap();
+        assert!(v6.contains(&"2001:db8:7fff::1".parse().unwrap()));
+        assert!(!v6.contains(&"2001:db8:8000::1".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<IpCidr>().is_err());
+        assert!("not-an-ip".parse::<IpCidr>().is_err());
+    }
+
+    #[test]
+    fn forwarding_headers_should_only_be_trusted_from_proxies() {
+        let trusted: Vec<IpCidr> = vec!["10.0.0.0/8".parse().unwrap()];
+        let xff = headers("X-Forwarded-For", "6.6.6.6, 203.0.113.7, 10.0.0.2");
+
+        let client = resolve_client_ip("10.0.0.1".parse().unwrap(), &xff, &trusted);
+        assert_eq!(client, "203.0.113.7".parse::<IpAddr>().unwrap());
+
+        let client = resolve_client_ip("198.51.100.1".parse().unwrap(), &xff, &trusted);
+        assert_eq!(client, "198.51.100.1".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn forwarded_header_should_be_parsed() {
+        let trusted: Vec<IpCidr> = vec!["10.0.0.1".parse().unwrap()];
+        let forwarded = headers("forwarded", "for=192.0.2.43, for=\"[2001:db8:cafe::17]:4711\";proto=https");
+        let client = resolve_client_ip("10.0.0.1".parse().unwrap(), &forwarded, &trusted);
+        assert_eq!(client, "2001:db8:cafe::17".parse::<IpAddr>().unwrap());
+    }
+}
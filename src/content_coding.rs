@@ -0,0 +1,132 @@
+use std::io::{Error, ErrorKind, Read, Result};
+use std::str::FromStr;
+
+#[derive(EnumString, Display, Debug, PartialEq, Eq, Clone, Copy)]
+#[strum(ascii_case_insensitive)]
+pub enum ContentCoding {
+    #[strum(serialize = "gzip", serialize = "x-gzip")]
+    Gzip,
+    #[strum(serialize = "deflate")]
+    Deflate,
+    #[strum(serialize = "br")]
+    Brotli,
+    #[strum(serialize = "zstd")]
+    Zstd,
+}
+
+pub const SUPPORTED_CODINGS: &str = "gzip, deflate, br, zstd";
+
+impl ContentCoding {
+    /// Parses a `Content-Encoding` header value into the list of codings in the
+    /// order they were applied. `identity` entries are skipped. Returns the
+    /// offending token if any coding is not supported.
+    pub fn parse_list(value: &str) -> std::result::Result<Vec<ContentCoding>, String> {
+        value
+            .split(',')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty() && !s.eq_ignore_ascii_case("identity"))
+            .map(|s| ContentCoding::from_str(s).map_err(|_| s.to_string()))
+            .collect()
+    }
+
+    /// Wraps `reader` so reads yield decoded bytes, failing with `InvalidData` once
+    /// the output grows past `limit` bytes.
+    pub fn decoder<'r>(&self, reader: impl Read + 'r, limit: usize) -> Result<Box<dyn Read + 'r>> {
+        let decoder: Box<dyn Read + 'r> = match self {
+            ContentCoding::Gzip => Box::new(flate2::read::MultiGzDecoder::new(reader)),
+            ContentCoding::Deflate => Box::new(flate2::read::ZlibDecoder::new(reader)),
+            ContentCoding::Brotli => Box::new(brotli::Decompressor::new(reader, 4096)),
+            ContentCoding::Zstd => Box::new(zstd::stream::read::Decoder::new(reader)?),
+        };
+        Ok(Box::new(Limited {
+            inner: decoder,
+            remaining: limit as u64,
+            limit,
+        }))
+    }
+
+    /// Decodes `data`, failing with `InvalidData` once the output grows past `limit` bytes.
+    pub fn decode(&self, data: &[u8], limit: usize) -> Result<Vec<u8>> {
+        let mut decoded = Vec::new();
+        self.decoder(data, limit)?
+            .read_to_end(&mut decoded)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        Ok(decoded)
+    }
+}
+
+/// Caps decoded output, so a small compressed body can't expand without bound.
+struct Limited<R: Read> {
+    inner: R,
+    remaining: u64,
+    limit: usize,
+}
+
+impl<R: Read> Read for Limited<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        // Reading one byte past the limit tells a body of exactly `limit` bytes apart
+        let len = buf.len().min(usize::try_from(self.remaining).unwrap_or(usize::MAX).saturating_add(1));
+        let n = self.inner.read(&mut buf[..len])?;
+        if n as u64 > self.remaining {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Decoded body exceeds {} bytes", self.limit),
+            ));
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn parse_list_should_skip_identity() {
+        let codings = ContentCoding::parse_list("identity, GZIP, br").unwrap();
+        assert_eq!(codings, vec![ContentCoding::Gzip, ContentCoding::Brotli]);
+    }
+
+    #[test]
+    fn parse_list_should_report_unsupported() {
+        assert_eq!(ContentCoding::parse_list("gzip, compress"), Err("compress".to_string()));
+    }
+
+    #[test]
+    fn decode_should_roundtrip_all_codings() {
+        let data = b"{\"items\": [1, 2, 3]}";
+
+        let mut deflate = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        deflate.write_all(data).unwrap();
+
+        let mut br = Vec::new();
+        brotli::CompressorWriter::new(&mut br, 4096, 5, 22).write_all(data).unwrap();
+
+        let zstd = zstd::stream::encode_all(&data[..], 0).unwrap();
+
+        assert_eq!(ContentCoding::Gzip.decode(&gzip(data), 1024).unwrap(), data);
+        assert_eq!(ContentCoding::Deflate.decode(&deflate.finish().unwrap(), 1024).unwrap(), data);
+        assert_eq!(ContentCoding::Brotli.decode(&br, 1024).unwrap(), data);
+        assert_eq!(ContentCoding::Zstd.decode(&zstd, 1024).unwrap(), data);
+    }
+
+    #[test]
+    fn decode_should_allow_unlimited_output() {
+        assert_eq!(ContentCoding::Gzip.decode(&gzip(b"abc"), usize::MAX).unwrap(), b"abc");
+    }
+
+    #[test]
+    fn decode_should_enforce_limit() {
+        let bomb = gzip(&vec![0u8; 1024 * 1024]);
+        let err = ContentCoding::Gzip.decode(&bomb, 1024).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,398 @@
+use crate::http_context::HttpContext;
+use crate::http_error::HttpError;
+use crate::http_request::HttpRequest;
+use crate::http_response::{HttpResponse, HttpStatusCode, IntoResponse};
+use crate::json::Json;
+use serde::de::DeserializeOwned;
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// A handler argument built from the request line, headers, path parameters or
+/// state. These leave the body alone, so a handler can take any number of them.
+pub trait FromRequestParts: Sized {
+    fn from_request_parts(request: &HttpRequest, context: &HttpContext) -> Result<Self, HttpError>;
+
+    /// Whether the input is missing altogether, which is what makes `Option<Self>`
+    /// `None`. Input that is present but invalid is still an error.
+    fn is_absent(_request: &HttpRequest, _context: &HttpContext) -> bool {
+        false
+    }
+}
+
+/// Marks extractors that only look at the request parts.
+pub struct ViaParts;
+
+/// Marks extractors that consume the request body.
+pub struct ViaBody;
+
+/// A handler argument built from the whole request. Body extractors implement this
+/// with [`ViaBody`] and can only be the last argument, since the body can be read
+/// once; every [`FromRequestParts`] type implements it with [`ViaParts`]. Failures
+/// become the response.
+pub trait FromRequest<M = ViaBody>: Sized {
+    fn from_request(request: &mut HttpRequest, context: &HttpContext) -> Result<Self, HttpError>;
+}
+
+impl<T: FromRequestParts> FromRequest<ViaParts> for T {
+    fn from_request(request: &mut HttpRequest, context: &HttpContext) -> Result<Self, HttpError> {
+        T::from_request_parts(request, context)
+    }
+}
+
+/// Marks the plain `(&mut HttpRequest, &HttpContext)` handler form.
+pub struct RawHandler;
+
+/// Anything that can serve a route: a function of `(&mut HttpRequest, &HttpContext)`
+/// or of up to six extractors, returning anything that implements [`IntoResponse`].
+pub trait Handler<Args>: Send + Sync + 'static {
+    fn call(&self, request: &mut HttpRequest, context: &HttpContext) -> HttpResponse;
+}
+
+impl<F, R> Handler<RawHandler> for F
+where
+    F: Fn(&mut HttpRequest, &HttpContext) -> R + Send + Sync + 'static,
+    R: IntoResponse,
+{
+    fn call(&self, request: &mut HttpRequest, context: &HttpContext) -> HttpResponse {
+        self(request, context).into_response()
+    }
+}
+
+impl<F, R> Handler<()> for F
+where
+    F: Fn() -> R + Send + Sync + 'static,
+    R: IntoResponse,
+{
+    fn call(&self, _: &mut HttpRequest, _: &HttpContext) -> HttpResponse {
+        self().into_response()
+    }
+}
+
+macro_rules! extractor_handler {
+    ([$($arg:ident),*] $last:ident) => {
+        #[allow(non_snake_case)]
+        impl<F, R, M, $($arg,)* $last> Handler<(M, $($arg,)* $last)> for F
+        where
+            F: Fn($($arg,)* $last) -> R + Send + Sync + 'static,
+            R: IntoResponse,
+            $($arg: FromRequestParts,)*
+            $last: FromRequest<M>,
+        {
+            fn call(&self, request: &mut HttpRequest, context: &HttpContext) -> HttpResponse {
+                $(
+                    let $arg = match $arg::from_request_parts(request, context) {
+                        Ok(value) => value,
+                        Err(error) => return error.into_response(),
+                    };
+                )*
+                let $last = match $last::from_request(request, context) {
+                    Ok(value) => value,
+                    Err(error) => return error.into_response(),
+                };
+                self($($arg,)* $last).into_response()
+            }
+        }
+    };
+}
+
+extractor_handler!([] A1);
+extractor_handler!([A1] A2);
+extractor_handler!([A1, A2] A3);
+extractor_handler!([A1, A2, A3] A4);
+extractor_handler!([A1, A2, A3, A4] A5);
+extractor_handler!([A1, A2, A3, A4, A5] A6);
+
+/// `None` when the input is absent, e.g. a missing header; present but invalid
+/// input is still rejected.
+impl<T: FromRequestParts> FromRequestParts for Option<T> {
+    fn from_request_parts(request: &HttpRequest, context: &HttpContext) -> Result<Self, HttpError> {
+        if T::is_absent(request, context) {
+            return Ok(None);
+        }
+        T::from_request_parts(request, context).map(Some)
+    }
+}
+
+/// `None` when the request has no body; a body that doesn't parse is still rejected.
+impl<T: FromRequest<ViaBody>> FromRequest<ViaBody> for Option<T> {
+    fn from_request(request: &mut HttpRequest, context: &HttpContext) -> Result<Self, HttpError> {
+        if request.content.remaining() == 0 && !request.content.is_read {
+            return Ok(None);
+        }
+        T::from_request(request, context).map(Some)
+    }
+}
+
+/// The query string deserialized into `T`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequestParts for Query<T> {
+    fn from_request_parts(request: &HttpRequest, _: &HttpContext) -> Result<Self, HttpError> {
+        serde_urlencoded::from_str(&request.query)
+            .map(Query)
+            .map_err(|e| HttpError::bad_request(&format!("Invalid query string: {}", e)))
+    }
+
+    fn is_absent(request: &HttpRequest, _: &HttpContext) -> bool {
+        request.query.is_empty()
+    }
+}
+
+/// Path parameters such as `{message}` deserialized into a struct or map `T`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Path<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequestParts for Path<T> {
+    fn from_request_parts(_: &HttpRequest, context: &HttpContext) -> Result<Self, HttpError> {
+        // Round-tripping through urlencoded lets numeric fields parse from the strings
+        let encoded = serde_urlencoded::to_string(context.get_path_params())
+            .map_err(|e| HttpError::bad_request(&format!("Invalid path parameters: {}", e)))?;
+        serde_urlencoded::from_str(&encoded)
+            .map(Path)
+            .map_err(|e| HttpError::bad_request(&format!("Invalid path parameters: {}", e)))
+    }
+}
+
+fn has_content_type(request: &HttpRequest, expected: &str) -> bool {
+    request
+        .get_header("Content-Type")
+        .and_then(|v| v.split(';').next())
+        .map(|t| {
+            let t = t.trim();
+            t.eq_ignore_ascii_case(expected) || (expected == "application/json" && t.ends_with("+json"))
+        })
+        .unwrap_or(false)
+}
+
+fn read_body(request: &mut HttpRequest) -> Result<Vec<u8>, HttpError> {
+    request
+        .content
+        .to_bytes()
+        .map_err(|e| HttpError::bad_request(&format!("Failed to read request body: {}", e)))
+}
+
+/// The body deserialized from JSON. Malformed JSON is a 400, JSON of the wrong
+/// shape a 422.
+impl<T: DeserializeOwned> FromRequest<ViaBody> for Json<T> {
+    fn from_request(request: &mut HttpRequest, _: &HttpContext) -> Result<Self, HttpError> {
+        if !has_content_type(request, "application/json") {
+            return Err(HttpError::new(
+                HttpStatusCode::UnsupportedMediaType,
+                "Expected a Content-Type of application/json",
+            ));
+        }
+        let body = read_body(request)?;
+        serde_json::from_slice(&body).map(Json).map_err(|e| {
+            let status = if e.is_data() {
+                HttpStatusCode::UnprocessableContent
+            } else {
+                HttpStatusCode::BadRequest
+            };
+            HttpError::new(status, &format!("Invalid JSON body: {}", e))
+        })
+    }
+}
+
+/// An `application/x-www-form-urlencoded` body deserialized into `T`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Form<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest<ViaBody> for Form<T> {
+    fn from_request(request: &mut HttpRequest, _: &HttpContext) -> Result<Self, HttpError> {
+        if !has_content_type(request, "application/x-www-form-urlencoded") {
+            return Err(HttpError::new(
+                HttpStatusCode::UnsupportedMediaType,
+                "Expected a Content-Type of application/x-www-form-urlencoded",
+            ));
+        }
+        let body = read_body(request)?;
+        serde_urlencoded::from_bytes(&body).map(Form).map_err(|e| {
+            HttpError::new(HttpStatusCode::UnprocessableContent, &format!("Invalid form body: {}", e))
+        })
+    }
+}
+
+/// A header with a known name and a typed value, used through [`Header`].
+pub trait TypedHeader: Sized {
+    const NAME: &'static str;
+    fn decode(value: &str) -> Result<Self, String>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserAgent(pub String);
+
+impl TypedHeader for UserAgent {
+    const NAME: &'static str = "User-Agent";
+
+    fn decode(value: &str) -> Result<Self, String> {
+        Ok(UserAgent(value.to_string()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContentLength(pub u64);
+
+impl TypedHeader for ContentLength {
+    const NAME: &'static str = "Content-Length";
+
+    fn decode(value: &str) -> Result<Self, String> {
+        value.trim().parse().map(ContentLength).map_err(|_| "not a number".to_string())
+    }
+}
+
+/// A required header; wrap in `Option` when it may be absent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header<T>(pub T);
+
+impl<T: TypedHeader> FromRequestParts for Header<T> {
+    fn from_request_parts(request: &HttpRequest, _: &HttpContext) -> Result<Self, HttpError> {
+        let value = request
+            .get_header(T::NAME)
+            .ok_or_else(|| HttpError::bad_request(&format!("Missing {} header", T::NAME)))?;
+        T::decode(value)
+            .map(Header)
+            .map_err(|e| HttpError::bad_request(&format!("Invalid {} header: {}", T::NAME, e)))
+    }
+
+    fn is_absent(request: &HttpRequest, _: &HttpContext) -> bool {
+        request.get_header(T::NAME).is_none()
+    }
+}
+
+/// Shared state registered with `HttpServer::add_state`.
+#[derive(Debug)]
+pub struct State<T>(pub Arc<T>);
+
+impl<T> Deref for State<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Send + Sync + 'static> FromRequestParts for State<T> {
+    fn from_request_parts(_: &HttpRequest, context: &HttpContext) -> Result<Self, HttpError> {
+        // A missing state is a setup mistake, not the client's
+        context.get_state::<T>().map(State).ok_or_else(|| {
+            HttpError::new(
+                HttpStatusCode::InternalServerError,
+                &format!("No state of type {} registered", std::any::type_name::<T>()),
+            )
+        })
+    }
+
+    fn is_absent(_: &HttpRequest, context: &HttpContext) -> bool {
+        context.get_state::<T>().is_none()
+    }
+}
+
+/// The raw request body, decoded from any `Content-Encoding`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BodyBytes(pub Vec<u8>);
+
+impl FromRequest<ViaBody> for BodyBytes {
+    fn from_request(request: &mut HttpRequest, _: &HttpContext) -> Result<Self, HttpError> {
+        read_body(request).map(BodyBytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::collections::HashMap;
+    use std::io::Cursor;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Transfer {
+        to: String,
+        amount: u32,
+    }
+
+    fn call<Args>(handler: impl Handler<Args>, raw: &str, context: &HttpContext) -> HttpResponse {
+        let mut reader = Cursor::new(raw.as_bytes());
+        let mut request = HttpRequest::from_reader(&mut reader).unwrap();
+        handler.call(&mut request, context)
+    }
+
+    fn body(response: &HttpResponse) -> String {
+        String::from_utf8_lossy(response.get_body().unwrap()).to_string()
+    }
+
+    #[test]
+    fn query_and_path_should_deserialize_into_structs() {
+        let handler = |Query(t): Query<Transfer>| format!("{} {}", t.to, t.amount);
+        let context = HttpContext::new_with_params(HashMap::new());
+        let response = call(handler, "GET /t?to=bob&amount=5 HTTP/1.1\r\n\r\n", &context);
+        assert_eq!(body(&response), "bob 5");
+        let response = call(handler, "GET /t?to=bob&amount=lots HTTP/1.1\r\n\r\n", &context);
+        assert_eq!(response.status_code(), HttpStatusCode::BadRequest);
+
+        let params = HashMap::from([("to".to_string(), "ann".to_string()), ("amount".to_string(), "7".to_string())]);
+        let context = HttpContext::new_with_params(params);
+        let response = call(|Path(t): Path<Transfer>| format!("{} {}", t.to, t.amount), "GET / HTTP/1.1\r\n\r\n", &context);
+        assert_eq!(body(&response), "ann 7");
+    }
+
+    #[test]
+    fn json_errors_should_distinguish_syntax_from_data() {
+        let handler = |Json(t): Json<Transfer>| Json(t.amount);
+        let context = HttpContext::new_with_params(HashMap::new());
+        let json = |body: &str| format!("POST / HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+
+        assert_eq!(body(&call(handler, &json(r#"{"to":"bob","amount":5}"#), &context)), "5");
+        assert_eq!(call(handler, &json(r#"{"to":"bob""#), &context).status_code(), HttpStatusCode::BadRequest);
+        assert_eq!(call(handler, &json(r#"{"to":"bob"}"#), &context).status_code(), HttpStatusCode::UnprocessableContent);
+        assert_eq!(
+            call(handler, "POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}", &context).status_code(),
+            HttpStatusCode::UnsupportedMediaType
+        );
+    }
+
+    #[test]
+    fn form_header_state_and_body_should_extract() {
+        let raw = "POST / HTTP/1.1\r\nUser-Agent: curl\r\nContent-Type: application/x-www-form-urlencoded\r\nContent-Length: 17\r\n\r\nto=eve&amount=12";
+        let context = HttpContext::new_with_params(HashMap::new());
+        let response = call(
+            |Header(UserAgent(agent)): Header<UserAgent>, Form(t): Form<Transfer>| format!("{} {} {}", agent, t.to, t.amount),
+            &format!("{}3", raw),
+            &context,
+        );
+        assert_eq!(body(&response), "curl eve 123");
+
+        let response = call(|_: Header<ContentLength>| "", "GET / HTTP/1.1\r\n\r\n", &context);
+        assert_eq!(response.status_code(), HttpStatusCode::BadRequest);
+        assert_eq!(body(&call(|h: Option<Header<ContentLength>>| format!("{:?}", h), "GET / HTTP/1.1\r\n\r\n", &context)), "None");
+
+        let mut states = crate::http_context::StateMap::new();
+        states.insert(std::any::TypeId::of::<String>(), Arc::new("greeting".to_string()));
+        let context = HttpContext::new_with_params(HashMap::new()).with_states(Arc::new(states));
+        let response = call(
+            |state: State<String>, BodyBytes(bytes): BodyBytes| format!("{} {}", *state, bytes.len()),
+            "POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc",
+            &context,
+        );
+        assert_eq!(body(&response), "greeting 3");
+        assert_eq!(
+            call(|_: State<u32>| "", "GET / HTTP/1.1\r\n\r\n", &context).status_code(),
+            HttpStatusCode::InternalServerError
+        );
+    }
+
+    #[test]
+    fn option_should_only_be_none_when_absent() {
+        let context = HttpContext::new_with_params(HashMap::new());
+        let handler = |q: Option<Query<Transfer>>, t: Option<Json<Transfer>>| format!("{:?} {:?}", q.is_some(), t.map(|Json(t)| t.amount));
+        assert_eq!(body(&call(handler, "GET / HTTP/1.1\r\n\r\n", &context)), "false None");
+        assert_eq!(
+            call(handler, "GET /?to=bob HTTP/1.1\r\n\r\n", &context).status_code(),
+            HttpStatusCode::BadRequest
+        );
+        let raw = "POST /?to=bob&amount=1 HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: 11\r\n\r\n{\"amount\":2";
+        assert_eq!(call(handler, raw, &context).status_code(), HttpStatusCode::BadRequest);
+        let raw = "POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}";
+        assert_eq!(call(handler, raw, &context).status_code(), HttpStatusCode::UnsupportedMediaType);
+    }
+}
@@ -0,0 +1,415 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Read, Result, Write};
+use std::path::Path;
+
+/// Fields of an `application/x-www-form-urlencoded` body, in order. A name may
+/// appear more than once, e.g. for checkboxes.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FormData {
+    fields: Vec<(String, String)>,
+}
+
+impl FormData {
+    pub fn parse(body: &[u8]) -> Result<Self> {
+        let fields = serde_urlencoded::from_bytes(body).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        Ok(FormData { fields })
+    }
+
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.fields
+            .iter()
+            .filter(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+            .collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+}
+
+/// The `boundary` parameter of a `multipart/form-data` content type.
+pub fn boundary(content_type: &str) -> Option<String> {
+    let mut params = split_params(content_type).into_iter();
+    if !params.next()?.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params
+        .filter_map(|param| param.split_once('=').map(|(k, v)| (k.trim().to_string(), v.trim().to_string())))
+        .find(|(k, _)| k.eq_ignore_ascii_case("boundary"))
+        .map(|(_, v)| unquote(&v))
+        // RFC 2046 section 5.1.1
+        .filter(|b| !b.is_empty() && b.len() <= 70)
+}
+
+/// Splits `value; a=b; c="d;e"` on the semicolons outside quotes.
+fn split_params(value: &str) -> Vec<String> {
+    let mut params = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    for c in value.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                params.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    params.push(current.trim().to_string());
+    params
+}
+
+fn unquote(value: &str) -> String {
+    match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(inner) => {
+            let mut unquoted = String::new();
+            let mut chars = inner.chars();
+            while let Some(c) = chars.next() {
+                unquoted.push(if c == '\\' { chars.next().unwrap_or(c) } else { c });
+            }
+            unquoted
+        }
+        None => value.to_string(),
+    }
+}
+
+/// Headers of one part of a multipart body, with the fields of its
+/// `Content-Disposition` picked out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Part {
+    pub headers: HashMap<String, String>,
+    pub name: Option<String>,
+    /// Present for file inputs. It is whatever the client sent, so don't use it as a path as is.
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+}
+
+impl Part {
+    fn parse(header_block: &str) -> Result<Self> {
+        let mut headers = HashMap::new();
+        for line in header_block.split("\r\n").filter(|l| !l.is_empty()) {
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("Malformed part header: {}", line)))?;
+            headers.insert(name.trim().to_string(), value.trim().to_string());
+        }
+        let mut part = Part {
+            headers,
+            name: None,
+            filename: None,
+            content_type: None,
+        };
+        part.content_type = part.get_header("Content-Type").cloned();
+        if let Some(disposition) = part.get_header("Content-Disposition").cloned() {
+            for param in split_params(&disposition).into_iter().skip(1) {
+                let Some((key, value)) = param.split_once('=') else {
+                    continue;
+                };
+                match key.trim().to_ascii_lowercase().as_str() {
+                    "name" => part.name = Some(unquote(value.trim())),
+                    "filename" => part.filename = Some(unquote(value.trim())),
+                    _ => {}
+                }
+            }
+        }
+        Ok(part)
+    }
+
+    /// Header lookup ignoring the case of the field name.
+    pub fn get_header(&self, name: &str) -> Option<&String> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.filename.is_some()
+    }
+}
+
+/// How much a multipart body may hold. Exceeding either fails the read with
+/// `ErrorKind::FileTooLarge`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MultipartLimits {
+    pub max_part_size: u64,
+    pub max_total_size: u64,
+}
+
+impl Default for MultipartLimits {
+    fn default() -> Self {
+        MultipartLimits {
+            max_part_size: 10 * 1024 * 1024,
+            max_total_size: 50 * 1024 * 1024,
+        }
+    }
+}
+
+const MAX_PART_HEADERS_SIZE: usize = 8 * 1024;
+const READ_CHUNK_SIZE: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MultipartState {
+    /// Inside the preamble or a part body, looking for the next delimiter.
+    Body,
+    Headers,
+    Done,
+}
+
+/// Reads a `multipart/form-data` body part by part without holding more than a
+/// few kilobytes of it in memory. Call [`Multipart::next_part`] for the headers,
+/// then one of the `read_*`/`save_to` methods for the content; content that isn't
+/// read is skipped.
+pub struct Multipart<R: Read> {
+    reader: R,
+    /// `\r\n--boundary`
+    delimiter: Vec<u8>,
+    buf: Vec<u8>,
+    state: MultipartState,
+    limits: MultipartLimits,
+    part_size: u64,
+    total_size: u64,
+}
+
+impl<R: Read> Multipart<R> {
+    pub fn new(reader: R, boundary: &str) -> Self {
+        Multipart {
+            reader,
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            // The first delimiter usually starts the body, without a CRLF before it
+            buf: b"\r\n".to_vec(),
+            state: MultipartState::Body,
+            limits: MultipartLimits::default(),
+            part_size: 0,
+            total_size: 0,
+        }
+    }
+
+    pub fn with_limits(mut self, limits: MultipartLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Headers of the next part, or `None` after the closing delimiter.
+    pub fn next_part(&mut self) -> Result<Option<Part>> {
+        if self.state == MultipartState::Body {
+            self.read_to(&mut std::io::sink())?;
+        }
+        if self.state == MultipartState::Done {
+            return Ok(None);
+        }
+        // The CRLF ending the delimiter line is still buffered, so an empty header
+        // block shows up as CRLF CRLF too
+        let end = loop {
+            if let Some(end) = find(&self.buf, b"\r\n\r\n") {
+                break end;
+            }
+            if self.buf.len() > MAX_PART_HEADERS_SIZE {
+                return Err(Error::new(ErrorKind::InvalidData, "Part headers are too large"));
+            }
+            if !self.fill()? {
+                return Err(unexpected_eof());
+            }
+        };
+        let part = Part::parse(&String::from_utf8_lossy(&self.buf[2..end]))?;
+        self.buf.drain(..end + 4);
+        self.state = MultipartState::Body;
+        self.part_size = 0;
+        Ok(Some(part))
+    }
+
+    /// Streams the content of the current part into `out`, returning its size.
+    pub fn read_to(&mut self, out: &mut dyn Write) -> Result<u64> {
+        let mut written = 0;
+        while self.state == MultipartState::Body {
+            let (chunk_len, found) = match find(&self.buf, &self.delimiter) {
+                Some(pos) => (pos, true),
+                // The tail might be the start of a delimiter split across reads
+                None => (self.buf.len().saturating_sub(self.delimiter.len() - 1), false),
+            };
+            self.count(chunk_len as u64)?;
+            out.write_all(&self.buf[..chunk_len])?;
+            written += chunk_len as u64;
+            if found {
+                self.buf.drain(..chunk_len + self.delimiter.len());
+                self.after_delimiter()?;
+            } else {
+                self.buf.drain(..chunk_len);
+                if !self.fill()? {
+                    return Err(unexpected_eof());
+                }
+            }
+        }
+        Ok(written)
+    }
+
+    pub fn read_bytes(&mut self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        self.read_to(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    pub fn read_string(&mut self) -> Result<String> {
+        Ok(String::from_utf8_lossy(&self.read_bytes()?).to_string())
+    }
+
+    /// Streams the content of the current part into a new file at `path`. The file
+    /// is removed again if the part can't be read completely.
+    pub fn save_to(&mut self, path: &Path) -> Result<u64> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let saved = self.read_to(&mut file).and_then(|size| file.flush().map(|_| size));
+        if saved.is_err() {
+            drop(file);
+            let _ = std::fs::remove_file(path);
+        }
+        saved
+    }
+
+    fn count(&mut self, len: u64) -> Result<()> {
+        self.part_size += len;
+        self.total_size += len;
+        if self.part_size > self.limits.max_part_size {
+            return Err(Error::new(
+                ErrorKind::FileTooLarge,
+                format!("Part exceeds {} bytes", self.limits.max_part_size),
+            ));
+        }
+        if self.total_size > self.limits.max_total_size {
+            return Err(Error::new(
+                ErrorKind::FileTooLarge,
+                format!("Multipart body exceeds {} bytes", self.limits.max_total_size),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Decides between `--` (the closing delimiter) and the CRLF before the next
+    /// part's headers, skipping the linear whitespace RFC 2046 allows in between.
+    fn after_delimiter(&mut self) -> Result<()> {
+        loop {
+            let padding = self.buf.iter().take_while(|b| **b == b' ' || **b == b'\t').count();
+            if self.buf.len() >= padding + 2 {
+                if self.buf.starts_with(b"--") {
+                    self.state = MultipartState::Done;
+                    return Ok(());
+                }
+                if &self.buf[padding..padding + 2] != b"\r\n" {
+                    return Err(Error::new(ErrorKind::InvalidData, "Malformed multipart delimiter"));
+                }
+                self.buf.drain(..padding);
+                self.state = MultipartState::Headers;
+                return Ok(());
+            }
+            if padding > MAX_PART_HEADERS_SIZE {
+                return Err(Error::new(ErrorKind::InvalidData, "Malformed multipart delimiter"));
+            }
+            if !self.fill()? {
+                return Err(unexpected_eof());
+            }
+        }
+    }
+
+    fn fill(&mut self) -> Result<bool> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        let n = self.reader.read(&mut chunk)?;
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(n > 0)
+    }
+}
+
+fn unexpected_eof() -> Error {
+    Error::new(ErrorKind::UnexpectedEof, "Multipart body ended before the closing delimiter")
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands out one byte per read, so every delimiter is split across reads.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            if self.0.is_empty() || buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = self.0[0];
+            self.0 = &self.0[1..];
+            Ok(1)
+        }
+    }
+
+    const BODY: &str = "preamble\r\n--XyZ\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nHello\r\n--XyZ\r\nContent-Disposition: form-data; name=\"upload\"; filename=\"a \\\"b\\\".txt\"\r\nContent-Type: text/plain\r\n\r\nline 1\r\n--XyXyZ\r\n--XyZ--\r\nepilogue";
+
+    #[test]
+    fn form_data_should_keep_repeated_fields() {
+        let form = FormData::parse(b"name=admin&shoesize=12&tag=a&tag=b%20c").unwrap();
+        assert_eq!(form.get_all("name"), vec!["admin"]);
+        assert_eq!(form.get_all("tag"), vec!["a", "b c"]);
+        assert_eq!(form.iter().count(), 4);
+        assert!(form.get_all("missing").is_empty());
+    }
+
+    #[test]
+    fn boundary_should_come_from_content_type() {
+        assert_eq!(boundary("multipart/form-data; boundary=XyZ"), Some("XyZ".to_string()));
+        assert_eq!(boundary("Multipart/Form-Data; charset=utf-8; boundary=\"a;b\""), Some("a;b".to_string()));
+        assert_eq!(boundary("multipart/mixed; boundary=XyZ"), None);
+        assert_eq!(boundary("multipart/form-data"), None);
+    }
+
+    #[test]
+    fn multipart_should_stream_parts() {
+        let mut multipart = Multipart::new(Trickle(BODY.as_bytes()), "XyZ");
+        let title = multipart.next_part().unwrap().unwrap();
+        assert_eq!(title.name.as_deref(), Some("title"));
+        assert!(!title.is_file());
+        assert_eq!(multipart.read_string().unwrap(), "Hello");
+
+        let upload = multipart.next_part().unwrap().unwrap();
+        assert_eq!(upload.filename.as_deref(), Some("a \"b\".txt"));
+        assert_eq!(upload.content_type.as_deref(), Some("text/plain"));
+        assert_eq!(multipart.read_string().unwrap(), "line 1\r\n--XyXyZ");
+        assert!(multipart.next_part().unwrap().is_none());
+
+        // Unread parts are skipped
+        let mut multipart = Multipart::new(BODY.as_bytes(), "XyZ");
+        multipart.next_part().unwrap();
+        assert_eq!(multipart.next_part().unwrap().unwrap().name.as_deref(), Some("upload"));
+    }
+
+    #[test]
+    fn multipart_should_enforce_limits() {
+        let limits = |part, total| MultipartLimits {
+            max_part_size: part,
+            max_total_size: total,
+        };
+        let mut multipart = Multipart::new(BODY.as_bytes(), "XyZ").with_limits(limits(10, 100));
+        multipart.next_part().unwrap();
+        multipart.next_part().unwrap();
+        assert_eq!(multipart.read_bytes().unwrap_err().kind(), ErrorKind::FileTooLarge);
+
+        let mut multipart = Multipart::new(BODY.as_bytes(), "XyZ").with_limits(limits(100, 20));
+        let path = std::env::temp_dir().join(format!("multipart-limit-{}", std::process::id()));
+        multipart.next_part().unwrap();
+        multipart.next_part().unwrap();
+        assert_eq!(multipart.save_to(&path).unwrap_err().kind(), ErrorKind::FileTooLarge);
+        assert!(!path.exists());
+
+        let mut truncated = Multipart::new(&BODY.as_bytes()[..67], "XyZ");
+        truncated.next_part().unwrap();
+        assert_eq!(truncated.read_bytes().unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+}
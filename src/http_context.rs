@@ -1,15 +1,39 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Application state registered on the server, one value per type.
+pub type StateMap = HashMap<TypeId, Arc<dyn Any + Send + Sync>>;
+
 pub struct HttpContext {
-    path_params: std::collections::HashMap<String, String>,   
+    path_params: std::collections::HashMap<String, String>,
+    states: Arc<StateMap>,
 }
 
 impl HttpContext {
     pub fn new_with_params(params: std::collections::HashMap<String, String>) -> Self {
         HttpContext {
             path_params: params,
+            states: Arc::new(StateMap::new()),
         }
     }
 
+    pub fn with_states(mut self, states: Arc<StateMap>) -> Self {
+        self.states = states;
+        self
+    }
+
     pub fn get_path_param(&self, key: &str) -> Option<&String> {
         self.path_params.get(key)
     }
-}
\ No newline at end of file
+
+    pub fn get_path_params(&self) -> &HashMap<String, String> {
+        &self.path_params
+    }
+
+    pub fn get_state<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        self.states
+            .get(&TypeId::of::<T>())
+            .and_then(|state| Arc::clone(state).downcast::<T>().ok())
+    }
+}
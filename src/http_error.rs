@@ -0,0 +1,169 @@
+use crate::http_response::{HttpResponse, HttpStatusCode, IntoResponse};
+use serde_json::{json, Value};
+use std::fmt;
+use std::sync::OnceLock;
+
+/// An error a handler can return instead of building the response itself. It is
+/// rendered by the app-wide [`ErrorRenderer`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpError {
+    pub status: HttpStatusCode,
+    pub message: String,
+    /// Extra members, e.g. which field was invalid.
+    pub details: Option<Value>,
+}
+
+impl HttpError {
+    pub fn new(status: HttpStatusCode, message: &str) -> Self {
+        HttpError {
+            status,
+            message: message.to_string(),
+            details: None,
+        }
+    }
+
+    pub fn bad_request(message: &str) -> Self {
+        HttpError::new(HttpStatusCode::BadRequest, message)
+    }
+
+    pub fn not_found(message: &str) -> Self {
+        HttpError::new(HttpStatusCode::NotFound, message)
+    }
+
+    pub fn with_details(mut self, details: Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}: {}", self.status.as_u16(), self.status, self.message)
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+impl IntoResponse for HttpError {
+    fn into_response(self) -> HttpResponse {
+        renderer().render(&self)
+    }
+}
+
+/// Turns [`HttpError`]s into responses, so every error in the app looks the same.
+pub trait ErrorRenderer: Send + Sync {
+    fn render(&self, error: &HttpError) -> HttpResponse;
+}
+
+/// `application/problem+json` bodies as described in RFC 9457. Details that are a
+/// JSON object become extension members, anything else goes under `details`.
+pub struct ProblemJsonRenderer;
+
+impl ErrorRenderer for ProblemJsonRenderer {
+    fn render(&self, error: &HttpError) -> HttpResponse {
+        let mut problem = json!({
+            "type": "about:blank",
+            "title": error.status.to_string(),
+            "status": error.status.as_u16(),
+            "detail": error.message,
+        });
+        match &error.details {
+            Some(Value::Object(members)) => {
+                for (key, value) in members {
+                    // The standard members can't be overridden
+                    if problem.get(key).is_none() {
+                        problem[key] = value.clone();
+                    }
+                }
+            }
+            Some(details) => problem["details"] = details.clone(),
+            None => {}
+        }
+        HttpResponse::new(error.status)
+            .with_body(&problem.to_string())
+            .with_header("Content-Type", "application/problem+json")
+    }
+}
+
+/// The message on one line, followed by the details if there are any.
+pub struct PlainTextRenderer;
+
+impl ErrorRenderer for PlainTextRenderer {
+    fn render(&self, error: &HttpError) -> HttpResponse {
+        let body = match &error.details {
+            Some(details) => format!("{}\n{}", error.message, details),
+            None => error.message.clone(),
+        };
+        HttpResponse::new(error.status).with_body(&body)
+    }
+}
+
+static RENDERER: OnceLock<Box<dyn ErrorRenderer>> = OnceLock::new();
+
+/// Picks how errors are rendered for the whole process; problem+json by default.
+pub fn set_renderer(renderer: Box<dyn ErrorRenderer>) {
+    let _ = RENDERER.set(renderer);
+}
+
+fn renderer() -> &'static dyn ErrorRenderer {
+    RENDERER.get_or_init(|| Box::new(ProblemJsonRenderer)).as_ref()
+}
+
+/// Renderer choice for the command line: `problem+json` or `text`.
+#[derive(EnumString, Debug, Clone, Copy, PartialEq)]
+pub enum ErrorFormat {
+    #[strum(serialize = "problem+json", serialize = "json")]
+    ProblemJson,
+    #[strum(serialize = "text")]
+    Text,
+}
+
+impl ErrorFormat {
+    pub fn renderer(self) -> Box<dyn ErrorRenderer> {
+        match self {
+            ErrorFormat::ProblemJson => Box::new(ProblemJsonRenderer),
+            ErrorFormat::Text => Box::new(PlainTextRenderer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn problem_json_should_follow_rfc_9457() {
+        let error = HttpError::new(HttpStatusCode::UnprocessableContent, "Invalid amount")
+            .with_details(json!({ "field": "amount", "status": 999 }));
+        let response = ProblemJsonRenderer.render(&error);
+        assert_eq!(response.status_code(), HttpStatusCode::UnprocessableContent);
+        assert_eq!(response.get_header("Content-Type").unwrap(), "application/problem+json");
+        let body: Value = serde_json::from_slice(response.get_body().unwrap()).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "type": "about:blank",
+                "title": "Unprocessable Content",
+                "status": 422,
+                "detail": "Invalid amount",
+                "field": "amount",
+            })
+        );
+    }
+
+    #[test]
+    fn plain_text_should_include_details() {
+        let error = HttpError::bad_request("Division by zero").with_details(json!(["b"]));
+        let response = PlainTextRenderer.render(&error);
+        assert_eq!(response.get_body().unwrap(), b"Division by zero\n[\"b\"]");
+        assert_eq!(error.to_string(), "400 Bad Request: Division by zero");
+    }
+
+    #[test]
+    fn result_should_convert_either_side() {
+        let ok: Result<HttpResponse, HttpError> = Ok(HttpResponse::new(HttpStatusCode::OK));
+        assert_eq!(ok.into_response().status_code(), HttpStatusCode::OK);
+        let err: Result<HttpResponse, HttpError> = Err(HttpError::not_found("No such user"));
+        assert_eq!(err.into_response().status_code(), HttpStatusCode::NotFound);
+    }
+}
@@ -1,14 +1,26 @@
+use crate::content_coding::ContentCoding;
+use crate::form::{FormData, Multipart, MultipartLimits};
+use std::any::{Any, TypeId};
 use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Read, Result};
+use std::io::{BufRead, Read, Result, Write};
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 
-#[derive(EnumString, Debug, PartialEq, Display, Hash, Eq)]
+#[derive(EnumString, Debug, PartialEq, Display, Hash, Eq, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum HttpMethod {
     GET,
+    HEAD,
     POST,
     PUT,
     DELETE,
     OPTIONS,
+    PATCH,
+    TRACE,
+    CONNECT,
+    /// Any other method token, e.g. WebDAV's `PROPFIND`.
+    #[strum(default)]
+    Extension(String),
 }
 #[allow(dead_code)]
 pub struct HttpRequest<'a> {
@@ -17,8 +29,15 @@ pub struct HttpRequest<'a> {
     pub query: String,
     pub http_version: String,
     pub headers: HashMap<String, String>,
-    pub content: Box<HttpRequestContent<BufReader<&'a mut dyn Read>>>,
+    pub content: Box<HttpRequestContent<&'a mut dyn BufRead>>,
     pub query_params: HashMap<String, String>,
+    /// Address of the connected client, when the request came from a socket.
+    pub peer_addr: Option<SocketAddr>,
+    /// Address of our end of the connection.
+    pub local_addr: Option<SocketAddr>,
+    /// The original client: the peer, or the address forwarded by a trusted proxy.
+    pub client_ip: Option<IpAddr>,
+    extensions: HashMap<TypeId, Box<dyn Any>>,
 }
 
 use std::cell::Cell;
@@ -26,27 +45,148 @@ use std::vec;
 
 pub struct HttpRequestContent<T: BufRead> {
     body: Cell<T>,
-    content_length: usize,
+    remaining: usize,
+    continue_writer: Option<Box<dyn Write>>,
+    decoding: Vec<ContentCoding>,
+    max_decoded_size: usize,
     pub is_read: bool,
 }
 
 impl<T: BufRead> HttpRequestContent<T> {
+    #[allow(clippy::wrong_self_convention, dead_code)]
     pub fn to_string(&mut self) -> Result<String> {
         let bytes = self.to_bytes()?;
         Ok(String::from_utf8_lossy(&bytes).to_string())
     }
 
+    #[allow(clippy::wrong_self_convention)]
     pub fn to_bytes(&mut self) -> Result<Vec<u8>> {
-        let mut buf = vec![0; self.content_length];
+        self.send_continue()?;
+        let mut buf = vec![0; self.remaining];
         self.body.get_mut().read_exact(&mut buf)?;
+        self.remaining = 0;
         self.is_read = true;
+        for coding in self.decoding.iter().rev() {
+            buf = coding.decode(&buf, self.max_decoded_size)?;
+        }
         Ok(buf)
     }
+
+    /// Parses an `application/x-www-form-urlencoded` body.
+    #[allow(clippy::wrong_self_convention)]
+    pub fn to_form(&mut self) -> Result<FormData> {
+        FormData::parse(&self.to_bytes()?)
+    }
+
+    /// Reads a `multipart/form-data` body part by part, streamed from the connection
+    /// through any content codings to undo.
+    pub fn multipart(&mut self, boundary: &str, limits: MultipartLimits) -> Result<Multipart<Box<dyn Read + '_>>> {
+        self.send_continue()?;
+        let decoding = self.decoding.clone();
+        let max_decoded_size = self.max_decoded_size;
+        let mut reader: Box<dyn Read + '_> = Box::new(BodyReader { content: self });
+        for coding in decoding.iter().rev() {
+            reader = coding.decoder(reader, max_decoded_size)?;
+        }
+        Ok(Multipart::new(reader, boundary).with_limits(limits))
+    }
+
+    /// Defers a `100 Continue` interim response to `writer` until the body is first
+    /// read, so a request rejected without touching its body never gets one.
+    pub fn expect_continue(&mut self, writer: Box<dyn Write>) {
+        if self.remaining > 0 {
+            self.continue_writer = Some(writer);
+        }
+    }
+
+    /// Whether the client is still holding the body back waiting for `100 Continue`.
+    pub fn awaiting_continue(&self) -> bool {
+        self.continue_writer.is_some()
+    }
+
+    fn send_continue(&mut self) -> Result<()> {
+        if let Some(mut writer) = self.continue_writer.take() {
+            writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Body bytes still waiting on the connection.
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    /// Discards the unread part of the body so the connection is positioned at the
+    /// next request. Returns `false` without reading anything if more than `limit`
+    /// bytes are left, in which case the connection has to be closed.
+    pub fn drain(&mut self, limit: usize) -> bool {
+        if self.remaining > limit || self.awaiting_continue() {
+            return false;
+        }
+        let expected = self.remaining as u64;
+        let drained = std::io::copy(
+            &mut self.body.get_mut().take(expected),
+            &mut std::io::sink(),
+        );
+        self.remaining = 0;
+        matches!(drained, Ok(n) if n == expected)
+    }
+
+    /// Makes subsequent reads undo `codings` (listed in the order they were applied),
+    /// failing with `InvalidData` if the decoded body grows past `max_decoded_size`.
+    pub fn set_decoding(&mut self, codings: Vec<ContentCoding>, max_decoded_size: usize) {
+        self.decoding = codings;
+        self.max_decoded_size = max_decoded_size;
+    }
+}
+
+/// Reads the body straight from the connection, keeping `remaining` up to date.
+struct BodyReader<'b, T: BufRead> {
+    content: &'b mut HttpRequestContent<T>,
+}
+
+impl<T: BufRead> Read for BodyReader<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let len = buf.len().min(self.content.remaining);
+        let n = self.content.body.get_mut().read(&mut buf[..len])?;
+        self.content.remaining -= n;
+        self.content.is_read = true;
+        Ok(n)
+    }
 }
 
 impl<'a> HttpRequest<'a> {
-    pub fn from_reader(r: &'a mut dyn Read) -> Result<HttpRequest<'a>> {
-        let mut buf = BufReader::new(r);
+    /// Header lookup ignoring the case of the field name.
+    pub fn get_header(&self, name: &str) -> Option<&String> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+    }
+
+    /// Stores a value for later middlewares and handlers, one per type.
+    pub fn insert_extension<T: Any>(&mut self, value: T) {
+        self.extensions.insert(TypeId::of::<T>(), Box::new(value));
+    }
+
+    pub fn get_extension<T: Any>(&self) -> Option<&T> {
+        self.extensions
+            .get(&TypeId::of::<T>())
+            .and_then(|v| v.downcast_ref::<T>())
+    }
+
+    /// Whether the `Connection` header lists `token`, compared case-insensitively.
+    pub fn has_connection_token(&self, token: &str) -> bool {
+        self.get_header("Connection")
+            .map(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+            .unwrap_or(false)
+    }
+
+    /// Parses the next request from `r`. The reader is expected to live as long as the
+    /// connection so bytes it buffers past this request are kept for the next one.
+    pub fn from_reader(r: &'a mut dyn BufRead) -> Result<HttpRequest<'a>> {
+        let buf = r;
         let mut first_line = String::new();
         let _ = buf.read_line(&mut first_line)?;
         if first_line.is_empty() {
@@ -58,36 +198,75 @@ impl<'a> HttpRequest<'a> {
         first_line = String::from(first_line.trim_end());
         let (method, path, query, http_version, query_params) = process_start_line(first_line)?;
         let mut headers: HashMap<String, String> = HashMap::new();
+        let mut content_length: Option<usize> = None;
         let mut line = String::new();
         let mut read = buf.read_line(&mut line)?;
         while read > 0 && line.trim_end() != "" {
             let (n, v) = line.split_once(":").unwrap_or((&line, ""));
+            // Checked per line, since repeated fields collapse in the map
+            if n.eq_ignore_ascii_case("Content-Length") {
+                let length = parse_content_length(v.trim())?;
+                if content_length.is_some_and(|l| l != length) {
+                    return Err(invalid_framing("Conflicting Content-Length"));
+                }
+                content_length = Some(length);
+            }
             headers.insert(String::from(n), String::from(v.trim()));
             line = String::new();
             read = buf.read_line(&mut line)?;
         }
-
-        let content_length: usize = headers.get("Content-Length")
-            .unwrap_or(&"0".to_string())
-            .parse()
-            .unwrap_or(0);
+        // Without chunked decoding the body can't be delimited, so guessing would
+        // read the next request from the wrong place
+        if headers.keys().any(|n| n.eq_ignore_ascii_case("Transfer-Encoding")) {
+            return Err(invalid_framing("Transfer-Encoding is not supported"));
+        }
+        let content_length = content_length.unwrap_or(0);
 
         Ok(HttpRequest {
-            method: method,
-            path: path,
-            query: query,
-            http_version: http_version,
-            headers: headers,
+            method,
+            path,
+            query,
+            http_version,
+            headers,
             content: Box::new(HttpRequestContent {
                 body: Cell::new(buf),
-                content_length: content_length,
+                remaining: content_length,
+                continue_writer: None,
+                decoding: Vec::new(),
+                max_decoded_size: usize::MAX,
                 is_read: false,
             }),
-            query_params: query_params,
+            query_params,
+            peer_addr: None,
+            local_addr: None,
+            client_ip: None,
+            extensions: HashMap::new(),
         })
     }
 }
 
+fn invalid_framing(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+/// A `Content-Length` value; a list is accepted if all its members agree (RFC 9112 section 6.3).
+fn parse_content_length(value: &str) -> Result<usize> {
+    let mut length = None;
+    for member in value.split(',').map(str::trim) {
+        if member.is_empty() || !member.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid_framing(&format!("Invalid Content-Length: {}", value)));
+        }
+        let member = member
+            .parse()
+            .map_err(|_| invalid_framing(&format!("Invalid Content-Length: {}", value)))?;
+        if length.is_some_and(|l| l != member) {
+            return Err(invalid_framing("Conflicting Content-Length"));
+        }
+        length = Some(member);
+    }
+    length.ok_or_else(|| invalid_framing("Empty Content-Length"))
+}
+
 fn parse_query_string(query: &str) -> HashMap<String, String> {
     let mut map = HashMap::new();
     for pair in query.split('&') {
@@ -97,26 +276,49 @@ fn parse_query_string(query: &str) -> HashMap<String, String> {
     map
 }
 
-fn process_start_line(
-    s: String,
-) -> Result<(HttpMethod, String, String, String, HashMap<String, String>)> {
+/// `tchar` from RFC 9110 section 5.6.2.
+fn is_tchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+type StartLine = (HttpMethod, String, String, String, HashMap<String, String>);
+
+fn process_start_line(s: String) -> Result<StartLine> {
+    let malformed = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Malformed request line: {}", s),
+        )
+    };
     let mut parts = s.split(' ');
-    let method = HttpMethod::from_str(parts.next().unwrap()).unwrap();
-    let path_and_query = parts.next().unwrap();
+    let (Some(method_token), Some(path_and_query), Some(http_ver), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(malformed());
+    };
+    if method_token.is_empty() || !method_token.bytes().all(is_tchar) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Invalid method: {}", method_token),
+        ));
+    }
+    if path_and_query.is_empty() || !http_ver.starts_with("HTTP/") {
+        return Err(malformed());
+    }
+    let method = HttpMethod::from_str(method_token).unwrap();
     let path_and_query_split = path_and_query
         .split_once("?")
         .unwrap_or((path_and_query, ""));
     let path = path_and_query_split.0;
     let query = path_and_query_split.1;
     let query_params = parse_query_string(query);
-    let http_ver = parts.next().unwrap();
-    return Ok((
+    Ok((
         method,
         String::from(path),
         String::from(query),
         String::from(http_ver),
         query_params,
-    ));
+    ))
 }
 
 #[cfg(test)]
@@ -159,6 +361,169 @@ mod tests {
         assert_eq!(content_str, "name=admin&shoesize=12");
     }
 
+    #[test]
+    fn test_content_to_form() {
+        let requessst_str = "POST /api/user HTTP/1.1\r\nContent-Length: 32\r\nContent-Type: application/x-www-form-urlencoded\r\n\r\nname=admin&shoesize=12&name=root";
+        let mut reader = Cursor::new(requessst_str.as_bytes());
+        let request = HttpRequest::from_reader(&mut reader).unwrap();
+        let mut content = request.content;
+        let form = content.to_form().unwrap();
+        assert_eq!(form.get_all("shoesize"), vec!["12"]);
+        assert_eq!(form.get_all("name"), vec!["admin", "root"]);
+    }
+
+    #[test]
+    fn test_content_multipart() {
+        let body = "--b\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\r\nabc\r\n--b--\r\n";
+        let requessst_str = format!("POST /files HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}GET / HTTP/1.1\r\n\r\n", body.len(), body);
+        let mut reader = Cursor::new(requessst_str.as_bytes());
+        let mut request = HttpRequest::from_reader(&mut reader).unwrap();
+        let mut multipart = request.content.multipart("b", MultipartLimits::default()).unwrap();
+        assert_eq!(multipart.next_part().unwrap().unwrap().filename.as_deref(), Some("a.txt"));
+        assert_eq!(multipart.read_string().unwrap(), "abc");
+        assert!(multipart.next_part().unwrap().is_none());
+        drop(multipart);
+        // Nothing past the body was consumed
+        assert_eq!(request.content.remaining(), 0);
+        drop(request);
+        assert!(HttpRequest::from_reader(&mut reader).is_ok());
+    }
+
+    #[test]
+    fn test_content_multipart_decoding() {
+        use std::io::Write;
+        let body = "--b\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\r\nabc\r\n--b--\r\n";
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(body.as_bytes()).unwrap();
+        let encoded = encoder.finish().unwrap();
+        let mut raw = format!("POST /files HTTP/1.1\r\nContent-Length: {}\r\n\r\n", encoded.len()).into_bytes();
+        raw.extend_from_slice(&encoded);
+
+        let mut reader = Cursor::new(raw.clone());
+        let mut request = HttpRequest::from_reader(&mut reader).unwrap();
+        request.content.set_decoding(vec![ContentCoding::Gzip], 1024);
+        let mut multipart = request.content.multipart("b", MultipartLimits::default()).unwrap();
+        assert_eq!(multipart.next_part().unwrap().unwrap().filename.as_deref(), Some("a.txt"));
+        assert_eq!(multipart.read_string().unwrap(), "abc");
+
+        let mut reader = Cursor::new(raw);
+        let mut request = HttpRequest::from_reader(&mut reader).unwrap();
+        request.content.set_decoding(vec![ContentCoding::Gzip], 16);
+        let mut multipart = request.content.multipart("b", MultipartLimits::default()).unwrap();
+        assert_eq!(multipart.next_part().unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_content_decoding() {
+        use std::io::Write;
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(b"[1, 2, 3]").unwrap();
+        let body = encoder.finish().unwrap();
+        let mut requessst = format!(
+            "POST /batch HTTP/1.1\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        requessst.extend_from_slice(&body);
+        let mut reader = Cursor::new(requessst);
+        let request = HttpRequest::from_reader(&mut reader).unwrap();
+        let mut content = request.content;
+        content.set_decoding(vec![ContentCoding::Gzip], 1024);
+        assert_eq!(content.to_string().unwrap(), "[1, 2, 3]");
+    }
+
+    #[test]
+    fn from_reader_extension_method() {
+        let requessst_str = "PROPFIND /dav HTTP/1.1\r\nDepth: 1\r\n\r\n";
+        let mut reader = Cursor::new(requessst_str.as_bytes());
+        let request = HttpRequest::from_reader(&mut reader).unwrap();
+        assert_eq!(request.method, HttpMethod::Extension("PROPFIND".to_string()));
+        assert_eq!(request.method.to_string(), "PROPFIND");
+
+        let mut reader = Cursor::new("PATCH /dav HTTP/1.1\r\n\r\n".as_bytes());
+        let request = HttpRequest::from_reader(&mut reader).unwrap();
+        assert_eq!(request.method, HttpMethod::PATCH);
+    }
+
+    #[test]
+    fn from_reader_invalid_method() {
+        let mut reader = Cursor::new("GE(T /dav HTTP/1.1\r\n\r\n".as_bytes());
+        assert!(HttpRequest::from_reader(&mut reader).is_err());
+    }
+
+    #[test]
+    fn from_reader_content_length() {
+        let mut reader = Cursor::new("POST / HTTP/1.1\r\ncontent-length: 5, 5\r\n\r\nhelloGET / HTTP/1.1\r\n\r\n".as_bytes());
+        let request = HttpRequest::from_reader(&mut reader).unwrap();
+        assert_eq!(request.content.remaining(), 5);
+        let mut content = request.content;
+        assert_eq!(content.to_string().unwrap(), "hello");
+
+        for headers in [
+            "Content-Length: abc\r\n",
+            "Content-Length: -1\r\n",
+            "Content-Length: +5\r\n",
+            "Content-Length: 5, 6\r\n",
+            "Content-Length: 5\r\ncontent-length: 6\r\n",
+            "Content-Length: 99999999999999999999999\r\n",
+            "Transfer-Encoding: chunked\r\n",
+        ] {
+            let raw = format!("POST / HTTP/1.1\r\n{}\r\n", headers);
+            let mut reader = Cursor::new(raw.as_bytes());
+            let error = HttpRequest::from_reader(&mut reader).err().unwrap();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidData, "{:?}", headers);
+        }
+    }
+
+    #[test]
+    fn from_reader_malformed_start_line() {
+        for line in ["GET\r\n", "\r\n", "GET /\r\n", "GET / HTTP/1.1 extra\r\n", "GET  HTTP/1.1\r\n", "GET / FTP/1.0\r\n"] {
+            let mut reader = Cursor::new(line.as_bytes());
+            let error = HttpRequest::from_reader(&mut reader).err().unwrap();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidData, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn drain_should_position_reader_at_next_request() {
+        let requessst_str = "POST /ignored HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET /next HTTP/1.1\r\n\r\n";
+        let mut reader = Cursor::new(requessst_str.as_bytes());
+        let mut request = HttpRequest::from_reader(&mut reader).unwrap();
+        assert_eq!(request.content.remaining(), 5);
+        assert!(!request.content.drain(4));
+        assert!(request.content.drain(5));
+        drop(request);
+        let request = HttpRequest::from_reader(&mut reader).unwrap();
+        assert_eq!(request.path, "/next");
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedWriter(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for SharedWriter {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn expect_continue_should_be_sent_on_first_read() {
+        let requessst_str = "POST /files/a HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\nhello";
+        let mut reader = Cursor::new(requessst_str.as_bytes());
+        let request = HttpRequest::from_reader(&mut reader).unwrap();
+        let written = SharedWriter::default();
+        let mut content = request.content;
+        content.expect_continue(Box::new(written.clone()));
+        assert!(content.awaiting_continue());
+        assert!(written.0.borrow().is_empty());
+        assert_eq!(content.to_string().unwrap(), "hello");
+        assert!(!content.awaiting_continue());
+        assert_eq!(written.0.borrow().as_slice(), b"HTTP/1.1 100 Continue\r\n\r\n");
+    }
+
     #[test]
     fn from_reader_query_params() {
         let requessst_str = "GET /qwe?p=1&p1=wer&q HTTP/1.1\r\nHost: 127.0.0.1:4221\r\nUser-Agent: curl/8.5.0\r\nAccept: */*\r\n\r\n";
@@ -1,33 +1,133 @@
+use crate::http_error::HttpError;
 use std::collections::HashMap;
 
-#[derive(Debug, Clone, Copy)]
-pub enum HttpStatusCode {
-    OK = 200,
-    Created = 201,
-    BadRequest = 400,
-    NotFound = 404,
-    InternalServerError = 500,
-    NotImplemented = 501,
-    ServiceUnavailable = 503,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HttpStatusCode(u16);
+
+macro_rules! status_codes {
+    ($(($name:ident, $code:expr, $reason:expr),)+) => {
+        #[allow(non_upper_case_globals)]
+        impl HttpStatusCode {
+            $(pub const $name: HttpStatusCode = HttpStatusCode($code);)+
+
+            /// Reason phrase registered with IANA for this code, if any.
+            pub fn canonical_reason(&self) -> Option<&'static str> {
+                match *self {
+                    $(HttpStatusCode::$name => Some($reason),)+
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+status_codes! {
+    (Continue, 100, "Continue"),
+    (SwitchingProtocols, 101, "Switching Protocols"),
+    (Processing, 102, "Processing"),
+    (EarlyHints, 103, "Early Hints"),
+    (OK, 200, "OK"),
+    (Created, 201, "Created"),
+    (Accepted, 202, "Accepted"),
+    (NonAuthoritativeInformation, 203, "Non-Authoritative Information"),
+    (NoContent, 204, "No Content"),
+    (ResetContent, 205, "Reset Content"),
+    (PartialContent, 206, "Partial Content"),
+    (MultiStatus, 207, "Multi-Status"),
+    (AlreadyReported, 208, "Already Reported"),
+    (IMUsed, 226, "IM Used"),
+    (MultipleChoices, 300, "Multiple Choices"),
+    (MovedPermanently, 301, "Moved Permanently"),
+    (Found, 302, "Found"),
+    (SeeOther, 303, "See Other"),
+    (NotModified, 304, "Not Modified"),
+    (UseProxy, 305, "Use Proxy"),
+    (TemporaryRedirect, 307, "Temporary Redirect"),
+    (PermanentRedirect, 308, "Permanent Redirect"),
+    (BadRequest, 400, "Bad Request"),
+    (Unauthorized, 401, "Unauthorized"),
+    (PaymentRequired, 402, "Payment Required"),
+    (Forbidden, 403, "Forbidden"),
+    (NotFound, 404, "Not Found"),
+    (MethodNotAllowed, 405, "Method Not Allowed"),
+    (NotAcceptable, 406, "Not Acceptable"),
+    (ProxyAuthenticationRequired, 407, "Proxy Authentication Required"),
+    (RequestTimeout, 408, "Request Timeout"),
+    (Conflict, 409, "Conflict"),
+    (Gone, 410, "Gone"),
+    (LengthRequired, 411, "Length Required"),
+    (PreconditionFailed, 412, "Precondition Failed"),
+    (ContentTooLarge, 413, "Content Too Large"),
+    (URITooLong, 414, "URI Too Long"),
+    (UnsupportedMediaType, 415, "Unsupported Media Type"),
+    (RangeNotSatisfiable, 416, "Range Not Satisfiable"),
+    (ExpectationFailed, 417, "Expectation Failed"),
+    (MisdirectedRequest, 421, "Misdirected Request"),
+    (UnprocessableContent, 422, "Unprocessable Content"),
+    (Locked, 423, "Locked"),
+    (FailedDependency, 424, "Failed Dependency"),
+    (TooEarly, 425, "Too Early"),
+    (UpgradeRequired, 426, "Upgrade Required"),
+    (PreconditionRequired, 428, "Precondition Required"),
+    (TooManyRequests, 429, "Too Many Requests"),
+    (RequestHeaderFieldsTooLarge, 431, "Request Header Fields Too Large"),
+    (UnavailableForLegalReasons, 451, "Unavailable For Legal Reasons"),
+    (InternalServerError, 500, "Internal Server Error"),
+    (NotImplemented, 501, "Not Implemented"),
+    (BadGateway, 502, "Bad Gateway"),
+    (ServiceUnavailable, 503, "Service Unavailable"),
+    (GatewayTimeout, 504, "Gateway Timeout"),
+    (HTTPVersionNotSupported, 505, "HTTP Version Not Supported"),
+    (VariantAlsoNegotiates, 506, "Variant Also Negotiates"),
+    (InsufficientStorage, 507, "Insufficient Storage"),
+    (LoopDetected, 508, "Loop Detected"),
+    (NotExtended, 510, "Not Extended"),
+    (NetworkAuthenticationRequired, 511, "Network Authentication Required"),
+}
+
+impl HttpStatusCode {
+    /// Any code in the RFC 9110 range 100..=599 is accepted, registered or not.
+    pub fn from_u16(code: u16) -> Option<Self> {
+        if (100..=599).contains(&code) {
+            Some(HttpStatusCode(code))
+        } else {
+            None
+        }
+    }
+
+    pub fn as_u16(&self) -> u16 {
+        self.0
+    }
+
+    pub fn is_informational(&self) -> bool {
+        (100..200).contains(&self.0)
+    }
+
+    pub fn is_server_error(&self) -> bool {
+        (500..600).contains(&self.0)
+    }
+
+    /// 1xx, 204 and 304 responses never carry content (RFC 9110 section 6.4.1).
+    pub fn allows_body(&self) -> bool {
+        !self.is_informational()
+            && *self != HttpStatusCode::NoContent
+            && *self != HttpStatusCode::NotModified
+    }
 }
 
 impl std::fmt::Display for HttpStatusCode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            HttpStatusCode::OK => write!(f, "OK"),
-            HttpStatusCode::Created => write!(f, "Created"),
-            HttpStatusCode::BadRequest => write!(f, "Bad Request"),
-            HttpStatusCode::NotFound => write!(f, "Not Found"),
-            HttpStatusCode::InternalServerError => write!(f, "Internal Server Error"),
-            HttpStatusCode::NotImplemented => write!(f, "Not Implemented"),
-            HttpStatusCode::ServiceUnavailable => write!(f, "Service Unavailable"),
+        match self.canonical_reason() {
+            Some(reason) => write!(f, "{}", reason),
+            None => write!(f, "{}", self.0),
         }
     }
 }
-    
 
 pub struct HttpResponse {
+    http_version: &'static str,
     status_code: HttpStatusCode,
+    reason_phrase: Option<String>,
     headers: HashMap<String, String>,
     body: Option<Vec<u8>>,
 }
@@ -35,18 +135,53 @@ pub struct HttpResponse {
 impl HttpResponse {
     pub fn new(status_code: HttpStatusCode) -> Self {
         let mut headers = HashMap::new();
-        headers.insert("Content-Length".to_string(), "0".to_string());
+        if status_code.allows_body() {
+            headers.insert("Content-Length".to_string(), "0".to_string());
+        }
         HttpResponse {
+            http_version: "HTTP/1.1",
             status_code,
+            reason_phrase: None,
             headers,
             body: None,
         }
     }
 
+    /// Builds a response whose status line carries `reason_phrase` instead of the
+    /// registered one; use with `HttpStatusCode::from_u16` for unregistered codes.
+    /// Control characters other than tab are stripped, so the phrase can't end the
+    /// status line early.
+    pub fn new_with_reason(status_code: HttpStatusCode, reason_phrase: &str) -> Self {
+        let mut response = HttpResponse::new(status_code);
+        let reason_phrase: String = reason_phrase.chars().filter(|c| *c == '\t' || !c.is_control()).collect();
+        response.reason_phrase = Some(reason_phrase);
+        response
+    }
+
     pub fn status_code(&self) -> HttpStatusCode {
         self.status_code
     }
 
+    /// Replaces the status, along with any custom reason phrase. Content-Length is
+    /// restored from the body when moving away from a status that carries none.
+    pub fn with_status(mut self, status_code: HttpStatusCode) -> Self {
+        self.status_code = status_code;
+        self.reason_phrase = None;
+        if status_code.allows_body() {
+            match &self.body {
+                Some(body) => self.set_header("Content-Length", &body.len().to_string()),
+                None => {
+                    self.headers.entry("Content-Length".to_string()).or_insert_with(|| "0".to_string());
+                }
+            }
+        }
+        self
+    }
+
+    pub fn set_http_version(&mut self, http_version: &'static str) {
+        self.http_version = http_version;
+    }
+
     pub fn set_header(&mut self, key: &str, value: &str) {
         self.headers.insert(key.to_string(), value.to_string());
     }
@@ -55,6 +190,10 @@ impl HttpResponse {
         self.headers.get(key)
     }
 
+    pub fn get_headers(&self) -> &HashMap<String, String> {
+        &self.headers
+    }
+
     pub fn with_header(mut self, key: &str, value: &str) -> Self {
         self.set_header(key, value);
         self
@@ -75,23 +214,203 @@ impl HttpResponse {
         self
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut response_str = format!("HTTP/1.1 {} {}\r\n", self.status_code as u16, self.status_code.to_string());
+    /// Status line and headers only, as sent in reply to HEAD.
+    pub fn to_head_bytes(&self) -> Vec<u8> {
+        let reason = self
+            .reason_phrase
+            .as_deref()
+            .or(self.status_code.canonical_reason())
+            .unwrap_or("");
+        let allows_body = self.status_code.allows_body();
+        let mut response_str = format!(
+            "{} {} {}\r\n",
+            self.http_version,
+            self.status_code.as_u16(),
+            reason
+        );
         for (key, value) in &self.headers {
+            if !allows_body && key.eq_ignore_ascii_case("Content-Length") {
+                continue;
+            }
             response_str.push_str(&format!("{}: {}\r\n", key, value));
         }
         response_str.push_str("\r\n");
-        let mut response = response_str.as_bytes().to_vec();
-        if let Some(body) = &self.body {
+        response_str.into_bytes()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut response = self.to_head_bytes();
+        if let Some(body) = &self.body
+            && self.status_code.allows_body()
+        {
             response.extend_from_slice(body);
         }
         response
     }
 }
 
+/// Anything a route handler may return.
+pub trait IntoResponse {
+    fn into_response(self) -> HttpResponse;
+}
+
+impl IntoResponse for HttpResponse {
+    fn into_response(self) -> HttpResponse {
+        self
+    }
+}
+
+impl IntoResponse for HttpStatusCode {
+    fn into_response(self) -> HttpResponse {
+        HttpResponse::new(self)
+    }
+}
+
+impl IntoResponse for &str {
+    fn into_response(self) -> HttpResponse {
+        HttpResponse::new(HttpStatusCode::OK).with_body(self)
+    }
+}
+
+impl IntoResponse for String {
+    fn into_response(self) -> HttpResponse {
+        HttpResponse::new(HttpStatusCode::OK).with_body(&self)
+    }
+}
+
+impl IntoResponse for Vec<u8> {
+    fn into_response(self) -> HttpResponse {
+        HttpResponse::new(HttpStatusCode::OK).with_bytes_body(self, "application/octet-stream")
+    }
+}
+
+impl<T: IntoResponse> IntoResponse for (HttpStatusCode, T) {
+    fn into_response(self) -> HttpResponse {
+        self.1.into_response().with_status(self.0)
+    }
+}
+
+/// `(status, headers, body)`, with headers as any list of name/value pairs such as
+/// `[("Location", "/files/a.txt")]`; they replace headers set by the body.
+impl<H, K, V, T> IntoResponse for (HttpStatusCode, H, T)
+where
+    H: IntoIterator<Item = (K, V)>,
+    K: AsRef<str>,
+    V: AsRef<str>,
+    T: IntoResponse,
+{
+    fn into_response(self) -> HttpResponse {
+        let mut response = self.2.into_response().with_status(self.0);
+        for (key, value) in self.1 {
+            response.set_header(key.as_ref(), value.as_ref());
+        }
+        response
+    }
+}
+
+/// `None` is rendered as a 404 error.
+impl<T: IntoResponse> IntoResponse for Option<T> {
+    fn into_response(self) -> HttpResponse {
+        match self {
+            Some(value) => value.into_response(),
+            None => HttpError::not_found("Not Found").into_response(),
+        }
+    }
+}
+
+impl<T: IntoResponse, E: IntoResponse> IntoResponse for Result<T, E> {
+    fn into_response(self) -> HttpResponse {
+        match self {
+            Ok(value) => value.into_response(),
+            Err(error) => error.into_response(),
+        }
+    }
+}
+
 impl From<HttpResponse> for Vec<u8> {
     fn from(response: HttpResponse) -> Self {
         response.to_bytes()
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_bytes_should_use_canonical_reason() {
+        let response = HttpResponse::new(HttpStatusCode::TooManyRequests).to_bytes();
+        assert!(response.starts_with(b"HTTP/1.1 429 Too Many Requests\r\n"));
+    }
+
+    #[test]
+    fn to_bytes_should_use_custom_reason() {
+        let status = HttpStatusCode::from_u16(599).unwrap();
+        let response = HttpResponse::new_with_reason(status, "Network Connect Timeout").to_bytes();
+        assert!(response.starts_with(b"HTTP/1.1 599 Network Connect Timeout\r\n"));
+    }
+
+    #[test]
+    fn custom_reason_should_not_split_the_response() {
+        let response = HttpResponse::new_with_reason(HttpStatusCode::OK, "Fine\r\nSet-Cookie: a=b\x7f\tok").to_bytes();
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 FineSet-Cookie: a=b\tok\r\n"));
+        assert_eq!(response.matches("\r\n").count(), 3);
+    }
+
+    #[test]
+    fn to_bytes_should_omit_body_for_no_content() {
+        let response = HttpResponse::new(HttpStatusCode::NoContent)
+            .with_body("ignored")
+            .to_bytes();
+        let response = String::from_utf8(response).unwrap();
+        assert!(!response.contains("Content-Length"));
+        assert!(response.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn to_head_bytes_should_keep_content_length() {
+        let response = HttpResponse::new(HttpStatusCode::OK).with_body("Hello").to_head_bytes();
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.contains("Content-Length: 5\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn into_response_should_convert_common_types() {
+        let response = "hi".into_response();
+        assert_eq!(response.status_code(), HttpStatusCode::OK);
+        assert_eq!(response.get_body().unwrap(), b"hi");
+
+        let response = vec![0u8, 1].into_response();
+        assert_eq!(response.get_header("Content-Type").unwrap(), "application/octet-stream");
+
+        let response = (HttpStatusCode::Created, [("Location", "/files/a.txt")], String::from("ok")).into_response();
+        assert_eq!(response.status_code(), HttpStatusCode::Created);
+        assert_eq!(response.get_header("Location").unwrap(), "/files/a.txt");
+        assert_eq!(response.get_header("Content-Length").unwrap(), "2");
+
+        assert_eq!(None::<String>.into_response().status_code(), HttpStatusCode::NotFound);
+        assert_eq!(Some("found").into_response().status_code(), HttpStatusCode::OK);
+    }
+
+    #[test]
+    fn status_code_classes() {
+        assert!(HttpStatusCode::EarlyHints.is_informational());
+        assert!(HttpStatusCode::GatewayTimeout.is_server_error());
+        assert!(!HttpStatusCode::Conflict.is_server_error());
+        assert_eq!(HttpStatusCode::from_u16(42), None);
+        assert_eq!(HttpStatusCode::from_u16(600), None);
+        assert_eq!(HttpStatusCode::from_u16(404), Some(HttpStatusCode::NotFound));
+    }
+
+    #[test]
+    fn with_status_should_restore_content_length() {
+        let response = HttpResponse::new(HttpStatusCode::NoContent).with_status(HttpStatusCode::OK);
+        assert_eq!(response.get_header("Content-Length").unwrap(), "0");
+
+        let response = (HttpStatusCode::NoContent, "gone").into_response().with_status(HttpStatusCode::OK);
+        assert_eq!(response.get_header("Content-Length").unwrap(), "4");
+        assert!(response.to_bytes().ends_with(b"\r\n\r\ngone"));
+    }
+}
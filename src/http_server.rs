@@ -1,16 +1,45 @@
+use crate::client_ip::{IpCidr, resolve_client_ip};
+use crate::extract::Handler;
 use crate::http_context::HttpContext;
+use crate::http_error::HttpError;
 use crate::http_request::{HttpMethod, HttpRequest};
-use crate::http_response::HttpResponse;
+use crate::http_response::{HttpResponse, HttpStatusCode, IntoResponse};
+use crate::logger::{RequestScope, log};
+use crate::metrics;
+use crate::panics;
 use crate::url_matcher::MatchMethod;
 use crate::middlewares::{HttpMiddleware, RoutingMiddleware};
-use std::io::{Write};
+use std::io::{BufReader, Read, Write};
 use std::net::TcpListener;
 use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
+
+/// Counts bytes read from the client into the metrics registry.
+struct CountingReader<R: Read>(R);
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.0.read(buf)?;
+        metrics::global().add_bytes_received(read);
+        Ok(read)
+    }
+}
+
+type MiddlewareChain = Box<dyn Fn(&mut HttpRequest) -> HttpResponse + Send + Sync>;
+
+#[derive(Clone)]
+struct ConnectionOptions {
+    keep_alive_timeout: Duration,
+    max_requests: usize,
+    max_drain_size: usize,
+    trusted_proxies: Vec<IpCidr>,
+}
 
 pub struct HttpServer {
     routing: Option<RoutingMiddleware>,
     middlewares: Option<Vec<Box<dyn HttpMiddleware + Send + Sync>>>,
+    connection_options: ConnectionOptions,
 }
 
 impl HttpServer {
@@ -19,15 +48,41 @@ impl HttpServer {
         HttpServer {
             routing: Some(RoutingMiddleware::new()),
             middlewares: Some(middlewares),
+            connection_options: ConnectionOptions {
+                keep_alive_timeout: Duration::from_secs(5),
+                max_requests: 100,
+                max_drain_size: 64 * 1024,
+                trusted_proxies: Vec::new(),
+            },
         }
     }
 
+    /// How long an idle persistent connection is kept open waiting for the next request.
+    pub fn set_keep_alive_timeout(&mut self, timeout: Duration) {
+        self.connection_options.keep_alive_timeout = timeout;
+    }
+
+    /// Number of requests served on one connection before it is closed.
+    pub fn set_max_requests_per_connection(&mut self, max_requests: usize) {
+        self.connection_options.max_requests = max_requests.max(1);
+    }
+
+    /// Largest unread request body that is discarded to keep the connection open;
+    /// connections with more left over are closed instead.
+    pub fn set_max_drain_size(&mut self, max_drain_size: usize) {
+        self.connection_options.max_drain_size = max_drain_size;
+    }
+
+    /// Peers whose `Forwarded`/`X-Forwarded-For` headers are used for `client_ip`.
+    pub fn set_trusted_proxies(&mut self, trusted_proxies: Vec<IpCidr>) {
+        self.connection_options.trusted_proxies = trusted_proxies;
+    }
+
     fn create_middleware_chain(
         vec: Vec<Box<dyn HttpMiddleware + Send + Sync>>,
-    ) -> Box<dyn Fn(&mut HttpRequest) -> HttpResponse + Send + Sync> {
-        let mut next_fn: Box<dyn Fn(&mut HttpRequest) -> HttpResponse + Send + Sync> =
-            Box::new(|_: &mut HttpRequest| {
-                HttpResponse::new(crate::http_response::HttpStatusCode::NotFound)
+    ) -> MiddlewareChain {
+        let mut next_fn: MiddlewareChain = Box::new(|req: &mut HttpRequest| {
+                HttpError::not_found(&format!("No route for {}", req.path)).into_response()
             });
         for mv in vec.into_iter() {
             let current_next = next_fn;
@@ -37,82 +92,371 @@ impl HttpServer {
     }
 
     fn handle_connection(
-        mut stream: std::net::TcpStream,
+        stream: std::net::TcpStream,
         middlewares_chain: &dyn Fn(&mut HttpRequest) -> HttpResponse,
+        options: &ConnectionOptions,
     ) {
-        while let Ok(request) = HttpRequest::from_reader(&mut stream) {
-            let mut req = request;
-            let close_connection = if req.http_version != "HTTP/1.1"
-                || req.headers.get("Connection").map(|s| s.to_string())
-                    == Some("close".to_string())
-            {
-                true
-            } else {
-                false
+        let _ = stream.set_read_timeout(Some(options.keep_alive_timeout));
+        let _connection = metrics::global().connection_opened();
+        let mut reader = BufReader::new(CountingReader(&stream));
+        let mut writer = &stream;
+        let peer_addr = stream.peer_addr().ok();
+        let local_addr = stream.local_addr().ok();
+        let mut served = 0;
+        // The reader outlives each request: pipelined requests that arrive in the same
+        // segment stay in its buffer and are answered in order on later iterations.
+        loop {
+            let mut req = match HttpRequest::from_reader(&mut reader) {
+                Ok(request) => request,
+                Err(e) => {
+                    // Anything else is a closed or idle connection, which gets no answer
+                    if e.kind() == std::io::ErrorKind::InvalidData {
+                        log!(Debug, "Malformed request", error = e.to_string());
+                        let bytes = HttpError::bad_request(&format!("Malformed request: {}", e))
+                            .into_response()
+                            .with_header("Connection", "close")
+                            .to_bytes();
+                        if writer.write_all(&bytes).and_then(|_| writer.flush()).is_ok() {
+                            metrics::global().add_bytes_sent(bytes.len());
+                        }
+                    }
+                    break;
+                }
             };
-            let mut response = middlewares_chain(&mut req);
-            // if !req.content.is_read {
-            //     let _ = req.content.to_string();
-            // }
-            if close_connection {
+            req.peer_addr = peer_addr;
+            req.local_addr = local_addr;
+            req.client_ip = peer_addr
+                .map(|peer| resolve_client_ip(peer.ip(), &req.headers, &options.trusted_proxies));
+            served += 1;
+            let _scope = RequestScope::enter(vec![
+                ("method", req.method.to_string().into()),
+                ("path", req.path.clone().into()),
+                ("peer", peer_addr.map(|p| p.to_string()).into()),
+            ]);
+            let is_http_10 = req.http_version == "HTTP/1.0";
+            let mut response = match req.get_header("Expect").map(|v| v.to_ascii_lowercase()) {
+                // HTTP/1.0 clients don't know about expectations, so theirs are ignored
+                Some(expect) if !is_http_10 && expect != "100-continue" => {
+                    HttpError::new(HttpStatusCode::ExpectationFailed, "Only 100-continue expectations are supported")
+                        .into_response()
+                }
+                Some(_) if !is_http_10 => match stream.try_clone() {
+                    Ok(continue_writer) => {
+                        req.content.expect_continue(Box::new(continue_writer));
+                        middlewares_chain(&mut req)
+                    }
+                    Err(_) => break,
+                },
+                _ => middlewares_chain(&mut req),
+            };
+            // A client still waiting for 100 Continue may or may not send the body
+            // after our final response, so the connection can't be reused.
+            let keep_alive = is_persistent(&req)
+                && served < options.max_requests
+                && !req.content.awaiting_continue()
+                && req.content.remaining() <= options.max_drain_size;
+            if keep_alive {
+                if is_http_10 || req.has_connection_token("keep-alive") {
+                    response = response.with_header("Connection", "keep-alive").with_header(
+                        "Keep-Alive",
+                        &format!(
+                            "timeout={}, max={}",
+                            options.keep_alive_timeout.as_secs(),
+                            options.max_requests - served
+                        ),
+                    );
+                }
+            } else {
                 response = response.with_header("Connection", "close");
             }
-            stream.write_all(&response.to_bytes()).unwrap();
-            stream.flush().unwrap();
-            if close_connection {
+            if is_http_10 {
+                response.set_http_version("HTTP/1.0");
+            }
+            let bytes = if req.method == HttpMethod::HEAD {
+                response.to_head_bytes()
+            } else {
+                response.to_bytes()
+            };
+            if writer.write_all(&bytes).and_then(|_| writer.flush()).is_err() {
+                break;
+            }
+            metrics::global().add_bytes_sent(bytes.len());
+            if !keep_alive || !req.content.drain(options.max_drain_size) {
                 break;
             }
         }
     }
 
     pub fn run(&mut self, addr: &str) {
+        let listener = TcpListener::bind(addr).unwrap();
+        log!(Info, "Server running", addr = addr);
+        self.serve(listener);
+    }
+
+    /// Accepts connections from an already bound listener until it fails.
+    pub fn serve(&mut self, listener: TcpListener) {
         self.middlewares
             .as_mut()
             .unwrap()
             .insert(0, Box::new(self.routing.take().unwrap()));
 
-        let middlewares_chain: Arc<
-            Box<dyn Fn(&mut HttpRequest<'_>) -> HttpResponse + Send + Sync>,
-        > = Arc::new(HttpServer::create_middleware_chain(
+        let middlewares_chain: Arc<MiddlewareChain> = Arc::new(HttpServer::create_middleware_chain(
             self.middlewares.take().unwrap(),
         ));
 
-        let listener = TcpListener::bind(addr).unwrap();
-        println!("Server running on {}", addr);
-        
+        let options = self.connection_options.clone();
         for stream in listener.incoming() {
             match stream {
                 Ok(mut _stream) => {
                     let middlewares_chain = Arc::clone(&middlewares_chain);
+                    let options = options.clone();
                     thread::spawn(move || {
-                        HttpServer::handle_connection(_stream, middlewares_chain.as_ref());
+                        // Panics outside PanicMiddleware, e.g. while parsing or writing,
+                        // only cost this connection
+                        let result = panics::catch(|| {
+                            HttpServer::handle_connection(_stream, middlewares_chain.as_ref(), &options)
+                        });
+                        if let Err(panic) = result {
+                            panic.log("Connection handler panicked");
+                        }
                     });
                 }
                 Err(e) => {
-                    println!("error: {}", e);
+                    log!(Error, "Failed to accept connection", error = e.to_string());
                 }
             }
         }
     }
 
-    pub fn add_route(
-        &mut self,
-        method: HttpMethod,
-        pattern: &str,
-        handler: fn(&mut HttpRequest, &HttpContext) -> HttpResponse,
-    ) {
-        self.routing.as_mut().unwrap().add_route(MatchMethod::from_method(method), pattern, handler);
+    /// Handlers may return anything that converts into a response, including
+    /// `Result<HttpResponse, HttpError>`. They either take the raw request and
+    /// context or up to six extractors such as `Query<T>` and `Json<T>`.
+    pub fn add_route<A, H: Handler<A>>(&mut self, method: HttpMethod, pattern: &str, handler: H) {
+        self.route(MatchMethod::from_method(method), pattern, handler);
+    }
+
+    fn route<A, H: Handler<A>>(&mut self, method: MatchMethod, pattern: &str, handler: H) {
+        self.routing.as_mut().unwrap().add_route(
+            method,
+            pattern,
+            Box::new(move |req: &mut HttpRequest, context: &HttpContext| handler.call(req, context)),
+        );
+    }
+
+    /// Registers a value handlers can receive through `State<T>`; one per type.
+    pub fn add_state<T: Send + Sync + 'static>(&mut self, state: T) {
+        self.routing.as_mut().unwrap().add_state(state);
     }
 
-    pub fn get(&mut self, pattern: &str, handler: fn(&mut HttpRequest, &HttpContext) -> HttpResponse) {
+    pub fn get<A, H: Handler<A>>(&mut self, pattern: &str, handler: H) {
         self.add_route(HttpMethod::GET, pattern, handler);
     }
 
-    pub fn post(&mut self, pattern: &str, handler: fn(&mut HttpRequest, &HttpContext) -> HttpResponse) {
+    pub fn post<A, H: Handler<A>>(&mut self, pattern: &str, handler: H) {
         self.add_route(HttpMethod::POST, pattern, handler);
     }
 
+    pub fn put<A, H: Handler<A>>(&mut self, pattern: &str, handler: H) {
+        self.add_route(HttpMethod::PUT, pattern, handler);
+    }
+
+    pub fn delete<A, H: Handler<A>>(&mut self, pattern: &str, handler: H) {
+        self.add_route(HttpMethod::DELETE, pattern, handler);
+    }
+
+    pub fn patch<A, H: Handler<A>>(&mut self, pattern: &str, handler: H) {
+        self.add_route(HttpMethod::PATCH, pattern, handler);
+    }
+
+    pub fn options<A, H: Handler<A>>(&mut self, pattern: &str, handler: H) {
+        self.add_route(HttpMethod::OPTIONS, pattern, handler);
+    }
+
+    pub fn any<A, H: Handler<A>>(&mut self, pattern: &str, handler: H) {
+        self.route(MatchMethod::ANY, pattern, handler);
+    }
+
     pub fn use_middleware(&mut self, middleware: Box<dyn HttpMiddleware + Send + Sync>) {
         self.middlewares.as_mut().unwrap().push(middleware);
     }
 }
+
+/// Connection persistence rules from RFC 9112 section 9.3.
+fn is_persistent(req: &HttpRequest) -> bool {
+    if req.has_connection_token("close") {
+        return false;
+    }
+    match req.http_version.as_str() {
+        "HTTP/1.1" => true,
+        "HTTP/1.0" => req.has_connection_token("keep-alive"),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn persistent(raw: &str) -> bool {
+        let mut reader = Cursor::new(raw.as_bytes());
+        is_persistent(&HttpRequest::from_reader(&mut reader).unwrap())
+    }
+
+    fn start_server(server: HttpServer) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = server;
+        thread::spawn(move || server.serve(listener));
+        addr
+    }
+
+    #[test]
+    fn pipelined_requests_should_be_answered_in_order() {
+        use std::io::Read;
+
+        let mut server = HttpServer::new();
+        server.get("/echo/{message}", |_: &mut HttpRequest, context: &HttpContext| {
+            HttpResponse::new(HttpStatusCode::OK)
+                .with_body(context.get_path_param("message").unwrap())
+        });
+        server.post("/ignore", |_: &mut HttpRequest, _: &HttpContext| {
+            HttpResponse::new(HttpStatusCode::Created)
+        });
+        let addr = start_server(server);
+
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(
+                b"GET /echo/one HTTP/1.1\r\n\r\n\
+                  POST /ignore HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody\
+                  GET /echo/two HTTP/1.1\r\n\r\n\
+                  HEAD /echo/three HTTP/1.1\r\n\r\n\
+                  GET /echo/four HTTP/1.1\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        let mut responses = String::new();
+        stream.read_to_string(&mut responses).unwrap();
+
+        let statuses: Vec<&str> = responses
+            .split("HTTP/1.1 ")
+            .skip(1)
+            .map(|r| r.lines().next().unwrap())
+            .collect();
+        assert_eq!(statuses, vec!["200 OK", "201 Created", "200 OK", "200 OK", "200 OK"]);
+        let bodies: Vec<&str> = responses
+            .split("HTTP/1.1 ")
+            .skip(1)
+            .map(|r| r.split_once("\r\n\r\n").unwrap().1)
+            .collect();
+        assert_eq!(bodies, vec!["one", "", "two", "", "four"]);
+    }
+
+    #[test]
+    fn expect_continue_should_be_answered_before_body() {
+        use std::io::{BufRead, Read};
+
+        let mut server = HttpServer::new();
+        server.post("/upload", |req: &mut HttpRequest, _: &HttpContext| {
+            HttpResponse::new(HttpStatusCode::OK).with_body(&req.content.to_string().unwrap())
+        });
+        server.post("/reject", |_: &mut HttpRequest, _: &HttpContext| {
+            HttpResponse::new(HttpStatusCode::Forbidden)
+        });
+        let addr = start_server(server);
+
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"POST /upload HTTP/1.1\r\nExpect: 100-continue\r\nConnection: close\r\nContent-Length: 5\r\n\r\n")
+            .unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "HTTP/1.1 100 Continue\r\n");
+        stream.write_all(b"hello").unwrap();
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert!(rest.starts_with("\r\nHTTP/1.1 200 OK\r\n"));
+        assert!(rest.ends_with("hello"));
+
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"POST /reject HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(!response.contains("100 Continue"));
+    }
+
+    #[test]
+    fn malformed_requests_should_get_400_and_close() {
+        use std::io::Read;
+
+        let addr = start_server(HttpServer::new());
+        for raw in [
+            "GET\r\n\r\n".as_bytes(),
+            b"\r\n",
+            b"GET / HTTP/1.1 x\r\n\r\n",
+            b"POST / HTTP/1.1\r\ncontent-length: abc\r\n\r\nGET / HTTP/1.1\r\n\r\n",
+        ] {
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            stream.write_all(raw).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{:?}", response);
+            assert!(response.contains("Connection: close\r\n"));
+        }
+    }
+
+    struct PanickingMiddleware;
+
+    impl HttpMiddleware for PanickingMiddleware {
+        fn handle(&self, request: &mut HttpRequest, next: &dyn Fn(&mut HttpRequest) -> HttpResponse) -> HttpResponse {
+            if request.path == "/boom" {
+                panic!("middleware failed");
+            }
+            next(request)
+        }
+    }
+
+    #[test]
+    fn panic_in_middleware_should_be_caught_and_counted() {
+        use crate::middlewares::{PanicMiddleware, StatisticMiddleware};
+        use std::io::Read;
+
+        let mut server = HttpServer::new();
+        let statistic = StatisticMiddleware::new();
+        server.use_middleware(Box::new(statistic.endpoint("/stats")));
+        server.use_middleware(Box::new(PanickingMiddleware));
+        server.use_middleware(Box::new(PanicMiddleware::new()));
+        server.use_middleware(Box::new(statistic));
+        let addr = start_server(server);
+
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /boom HTTP/1.1\r\n\r\nGET /stats?format=json HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut responses = String::new();
+        stream.read_to_string(&mut responses).unwrap();
+        let mut responses = responses.split("HTTP/1.1 ").skip(1);
+        let boom = responses.next().unwrap();
+        assert!(boom.starts_with("500 Internal Server Error\r\n"), "{}", boom);
+        assert!(boom.contains("\"error_id\":"));
+        let stats: serde_json::Value =
+            serde_json::from_str(responses.next().unwrap().split_once("\r\n\r\n").unwrap().1).unwrap();
+        assert_eq!(stats["panics"], 1);
+        assert_eq!(stats["requests_by_path"]["GET unmatched"], 1);
+    }
+
+    #[test]
+    fn is_persistent_should_follow_rfc9112() {
+        assert!(persistent("GET / HTTP/1.1\r\n\r\n"));
+        assert!(!persistent("GET / HTTP/1.1\r\nConnection: Close\r\n\r\n"));
+        assert!(persistent("GET / HTTP/1.1\r\nconnection: keep-alive, Upgrade\r\n\r\n"));
+        assert!(!persistent("GET / HTTP/1.0\r\n\r\n"));
+        assert!(persistent("GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n"));
+        assert!(!persistent("GET / HTTP/0.9\r\n\r\n"));
+    }
+}
@@ -0,0 +1,35 @@
+use crate::http_error::HttpError;
+use crate::http_response::{HttpResponse, HttpStatusCode, IntoResponse};
+use serde::Serialize;
+
+/// Serializes the wrapped value as an `application/json` response body.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Json<T>(pub T);
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> HttpResponse {
+        match serde_json::to_vec(&self.0) {
+            Ok(body) => HttpResponse::new(HttpStatusCode::OK).with_bytes_body(body, "application/json"),
+            Err(e) => HttpError::new(HttpStatusCode::InternalServerError, "Failed to serialize response")
+                .with_details(serde_json::Value::from(e.to_string()))
+                .into_response(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn json_should_serialize_body() {
+        let response = Json(BTreeMap::from([("sum", 3)])).into_response();
+        assert_eq!(response.get_header("Content-Type").unwrap(), "application/json");
+        assert_eq!(response.get_body().unwrap(), b"{\"sum\":3}");
+
+        let response = (HttpStatusCode::Created, Json(vec![1, 2])).into_response();
+        assert_eq!(response.status_code(), HttpStatusCode::Created);
+        assert_eq!(response.get_body().unwrap(), b"[1,2]");
+    }
+}
@@ -0,0 +1,125 @@
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::str::FromStr;
+use std::sync::mpsc::{Receiver, SyncSender, sync_channel};
+use std::thread;
+
+/// Where log lines end up.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LogSink {
+    Stdout,
+    /// Appends to the file at this path.
+    File(String),
+    /// The local syslog daemon via `/dev/log`.
+    Syslog,
+    /// Kept in [`TEST_LINES`] so tests don't print to the real stdout.
+    #[cfg(test)]
+    Memory,
+}
+
+#[cfg(test)]
+pub static TEST_LINES: std::sync::Mutex<Vec<String>> = std::sync::Mutex::new(Vec::new());
+
+impl FromStr for LogSink {
+    type Err = String;
+
+    /// `stdout`, `syslog`, or anything else as a file path.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" => Err("Empty log destination".to_string()),
+            "stdout" | "-" => Ok(LogSink::Stdout),
+            "syslog" => Ok(LogSink::Syslog),
+            path => Ok(LogSink::File(path.to_string())),
+        }
+    }
+}
+
+const QUEUE_SIZE: usize = 16 * 1024;
+
+/// Hands lines to a background thread so request threads never wait on I/O.
+/// Lines are dropped rather than blocking when the queue is full.
+#[derive(Clone)]
+pub struct LogWriter {
+    sender: SyncSender<String>,
+}
+
+impl LogWriter {
+    pub fn new(sink: LogSink, tag: &str) -> std::io::Result<Self> {
+        let output = Output::open(sink, tag)?;
+        let (sender, receiver) = sync_channel(QUEUE_SIZE);
+        thread::Builder::new()
+            .name(format!("{}-writer", tag))
+            .spawn(move || write_loop(receiver, output))?;
+        Ok(LogWriter { sender })
+    }
+
+    pub fn write_line(&self, line: String) {
+        // A full queue drops the line so request latency never depends on the sink
+        let _ = self.sender.try_send(line);
+    }
+}
+
+enum Output {
+    Stream(BufWriter<Box<dyn Write + Send>>),
+    #[cfg(unix)]
+    Syslog(std::os::unix::net::UnixDatagram, String),
+    #[cfg(test)]
+    Memory,
+}
+
+impl Output {
+    fn open(sink: LogSink, tag: &str) -> std::io::Result<Self> {
+        match sink {
+            LogSink::Stdout => Ok(Output::Stream(BufWriter::new(Box::new(std::io::stdout())))),
+            LogSink::File(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                Ok(Output::Stream(BufWriter::new(Box::new(file))))
+            }
+            #[cfg(unix)]
+            LogSink::Syslog => {
+                let socket = std::os::unix::net::UnixDatagram::unbound()?;
+                socket.connect("/dev/log")?;
+                Ok(Output::Syslog(socket, tag.to_string()))
+            }
+            #[cfg(not(unix))]
+            LogSink::Syslog => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "syslog is only available on unix",
+            )),
+            #[cfg(test)]
+            LogSink::Memory => Ok(Output::Memory),
+        }
+    }
+
+    fn write(&mut self, line: &str) {
+        match self {
+            Output::Stream(w) => {
+                let _ = w.write_all(line.as_bytes()).and_then(|_| w.write_all(b"\n"));
+            }
+            #[cfg(unix)]
+            Output::Syslog(socket, tag) => {
+                // RFC 3164 message with facility local0 (16) and severity info (6)
+                let _ = socket.send(format!("<134>{}: {}", tag, line).as_bytes());
+            }
+            #[cfg(test)]
+            Output::Memory => TEST_LINES.lock().unwrap().push(line.to_string()),
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Output::Stream(w) = self {
+            let _ = w.flush();
+        }
+    }
+}
+
+fn write_loop(receiver: Receiver<String>, mut output: Output) {
+    // Block for one line, then take whatever else is queued before flushing once
+    while let Ok(line) = receiver.recv() {
+        output.write(&line);
+        while let Ok(line) = receiver.try_recv() {
+            output.write(&line);
+        }
+        output.flush();
+    }
+}
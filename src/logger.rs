@@ -0,0 +1,166 @@
+use crate::log_writer::{LogSink, LogWriter};
+use crate::timestamp;
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::sync::OnceLock;
+use std::time::SystemTime;
+
+#[derive(EnumString, Display, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum Level {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+struct Logger {
+    level: Level,
+    writer: LogWriter,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+#[cfg(not(test))]
+const DEFAULT_SINK: LogSink = LogSink::Stdout;
+// Caught panics and rejected requests log multi-line errors that would drown the
+// test output
+#[cfg(test)]
+const DEFAULT_SINK: LogSink = LogSink::Memory;
+
+thread_local! {
+    // Connections are served on their own thread, so the request being handled
+    // is whatever the current thread last entered.
+    static REQUEST_FIELDS: RefCell<Vec<(&'static str, Value)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Sets up the process-wide logger. Until this is called, entries at `info` and
+/// above go to stdout.
+pub fn init(level: Level, sink: LogSink) -> std::io::Result<()> {
+    let logger = Logger {
+        level,
+        writer: LogWriter::new(sink, "server-log")?,
+    };
+    let _ = LOGGER.set(logger);
+    Ok(())
+}
+
+fn logger() -> &'static Logger {
+    LOGGER.get_or_init(|| Logger {
+        level: Level::Info,
+        writer: LogWriter::new(DEFAULT_SINK, "server-log").expect("Failed to start log writer"),
+    })
+}
+
+pub fn enabled(level: Level) -> bool {
+    level >= logger().level
+}
+
+/// Fields attached to every entry logged on this thread until the guard is dropped.
+pub struct RequestScope {
+    previous: Vec<(&'static str, Value)>,
+}
+
+impl RequestScope {
+    pub fn enter(fields: Vec<(&'static str, Value)>) -> Self {
+        let previous = REQUEST_FIELDS.with(|f| std::mem::replace(&mut *f.borrow_mut(), fields));
+        RequestScope { previous }
+    }
+}
+
+impl Drop for RequestScope {
+    fn drop(&mut self) {
+        let previous = std::mem::take(&mut self.previous);
+        REQUEST_FIELDS.with(|f| *f.borrow_mut() = previous);
+    }
+}
+
+/// Adds a field to, or replaces one in, the current request's scope.
+pub fn set_request_field(key: &'static str, value: Value) {
+    REQUEST_FIELDS.with(|f| {
+        let mut fields = f.borrow_mut();
+        match fields.iter_mut().find(|(k, _)| *k == key) {
+            Some(field) => field.1 = value,
+            None => fields.push((key, value)),
+        }
+    });
+}
+
+/// One JSON object: time, level and message first, then request fields, then
+/// the entry's own fields, which win on name clashes.
+fn format_entry(
+    time: SystemTime,
+    level: Level,
+    message: &str,
+    fields: Vec<(&'static str, Value)>,
+) -> String {
+    let mut entry = Map::new();
+    entry.insert("ts".to_string(), Value::from(timestamp::rfc3339(time)));
+    entry.insert("level".to_string(), Value::from(level.to_string()));
+    entry.insert("msg".to_string(), Value::from(message));
+    REQUEST_FIELDS.with(|f| {
+        for (key, value) in f.borrow().iter() {
+            entry.insert(key.to_string(), value.clone());
+        }
+    });
+    for (key, value) in fields {
+        entry.insert(key.to_string(), value);
+    }
+    Value::Object(entry).to_string()
+}
+
+pub fn event(level: Level, message: &str, fields: Vec<(&'static str, Value)>) {
+    if enabled(level) {
+        logger()
+            .writer
+            .write_line(format_entry(SystemTime::now(), level, message, fields));
+    }
+}
+
+/// `log!(Warn, "message", key = value, ...)`; values are anything `serde_json::json!` takes.
+macro_rules! log {
+    ($level:ident, $message:expr $(, $key:ident = $value:expr)* $(,)?) => {
+        if $crate::logger::enabled($crate::logger::Level::$level) {
+            $crate::logger::event(
+                $crate::logger::Level::$level,
+                &$message,
+                vec![$((stringify!($key), serde_json::json!($value))),*],
+            )
+        }
+    };
+}
+pub(crate) use log;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn level_should_parse_and_order() {
+        assert_eq!("WARN".parse::<Level>().unwrap(), Level::Warn);
+        assert!("verbose".parse::<Level>().is_err());
+        assert!(Level::Error > Level::Info);
+        assert_eq!(Level::Debug.to_string(), "debug");
+    }
+
+    #[test]
+    fn entry_should_include_request_fields_while_in_scope() {
+        let time = UNIX_EPOCH + Duration::from_secs(971_186_136);
+        {
+            let _scope = RequestScope::enter(vec![
+                ("method", Value::from("GET")),
+                ("path", Value::from("/echo/abc")),
+            ]);
+            assert_eq!(
+                format_entry(time, Level::Info, "hi", vec![("status", Value::from(200))]),
+                r#"{"ts":"2000-10-10T13:55:36.000000Z","level":"info","msg":"hi","method":"GET","path":"/echo/abc","status":200}"#
+            );
+        }
+        assert_eq!(
+            format_entry(time, Level::Error, "bye", Vec::new()),
+            r#"{"ts":"2000-10-10T13:55:36.000000Z","level":"error","msg":"bye"}"#
+        );
+    }
+}
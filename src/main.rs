@@ -1,105 +1,537 @@
+mod client_ip;
+mod content_coding;
+mod extract;
+mod form;
 mod http_context;
+mod http_error;
 mod http_request;
 mod http_response;
 mod http_server;
+mod json;
+mod log_writer;
+mod logger;
+mod metrics;
+mod panics;
 mod middlewares;
+mod timestamp;
+mod trace;
 mod url_matcher;
 
+use crate::extract::{BodyBytes, ContentLength, Form, Header, Path, Query, State, UserAgent};
+use crate::form::MultipartLimits;
 use crate::http_context::HttpContext;
-use crate::http_request::{HttpRequest};
+use crate::http_error::{ErrorFormat, HttpError};
+use crate::http_request::{HttpMethod, HttpRequest};
+use crate::json::Json;
 use crate::http_response::{HttpResponse, HttpStatusCode};
+use crate::log_writer::LogSink;
 use crate::middlewares::{
-    EncodingMiddleware, LoggingMiddleware, PanicMiddleware, StaticFilesMiddleware, StatisticMiddleware,
+    AccessLogFormat, AllowedOrigin, AuthMiddleware, CorsMiddleware, EncodingMiddleware, LoggingMiddleware, MetricsMiddleware, PanicMiddleware, RequestDecompressionMiddleware, RequestIdFormat, RequestIdMiddleware,
+    HtpasswdStore, IpFilterMiddleware, IpFilterRule, JwtClaims, JwtMiddleware, RateLimit, RateLimitKey,
+    RateLimitMiddleware, StaticFilesMiddleware, StatisticMiddleware, TracingMiddleware,
 };
 use clap::Parser;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
     #[arg(short, long, default_value = ".")]
     directory: String,
+    /// Seconds an idle keep-alive connection stays open
+    #[arg(long, default_value_t = 5)]
+    keep_alive_timeout: u64,
+    /// Requests served per connection before it is closed
+    #[arg(long, default_value_t = 100)]
+    max_requests_per_connection: usize,
+    /// Largest unread request body discarded to keep a connection alive
+    #[arg(long, default_value_t = 64 * 1024)]
+    max_drain_size: usize,
+    /// Origin allowed to make cross-origin requests (`*`, exact or `https://*.example.com`)
+    #[arg(long)]
+    cors_origin: Vec<String>,
+    /// Regular expression allowed origins must match, e.g. `^https://(app|admin)\.example\.com$`
+    #[arg(long)]
+    cors_origin_regex: Vec<regex::Regex>,
+    /// Allow `http://localhost` and `http://127.0.0.1` origins on any port
+    #[arg(long)]
+    cors_allow_localhost: bool,
+    /// Method cross-origin requests may use (defaults to GET, HEAD and POST)
+    #[arg(long)]
+    cors_method: Vec<HttpMethod>,
+    /// Request header cross-origin requests may send; `*` allows any
+    #[arg(long)]
+    cors_header: Vec<String>,
+    /// Response header scripts on allowed origins may read
+    #[arg(long)]
+    cors_expose_header: Vec<String>,
+    /// Let cross-origin requests carry cookies and credentials
+    #[arg(long)]
+    cors_credentials: bool,
+    /// Seconds browsers may cache a preflight response
+    #[arg(long)]
+    cors_max_age: Option<u64>,
+    /// htpasswd file guarding /stats, the metrics path and uploads to /files
+    #[arg(long)]
+    htpasswd: Option<String>,
+    /// Bearer token accepted for /stats, the metrics path and uploads to /files
+    #[arg(long)]
+    api_token: Vec<String>,
+    /// HS256 secret for JWTs guarding /whoami
+    #[arg(long)]
+    jwt_secret: Option<String>,
+    /// PEM file with an RS256 public key for JWTs guarding /whoami
+    #[arg(long)]
+    jwt_rs256_key: Option<String>,
+    /// PEM file with an ES256 public key for JWTs guarding /whoami
+    #[arg(long)]
+    jwt_es256_key: Option<String>,
+    /// JWKS file with keys for JWTs guarding /whoami
+    #[arg(long)]
+    jwks_file: Option<String>,
+    /// Scope JWTs must carry to reach /whoami
+    #[arg(long)]
+    jwt_scope: Vec<String>,
+    /// Clock skew tolerated when checking `exp` and `nbf`, in seconds
+    #[arg(long, default_value_t = 60)]
+    jwt_leeway: u64,
+    /// Required `iss` claim of JWTs
+    #[arg(long)]
+    jwt_issuer: Option<String>,
+    /// Required `aud` claim of JWTs
+    #[arg(long)]
+    jwt_audience: Option<String>,
+    /// Longest sleep /delay agrees to, in seconds
+    #[arg(long, default_value_t = 10)]
+    max_delay: u64,
+    /// Largest body /echo-body echoes, in bytes
+    #[arg(long, default_value_t = 64 * 1024)]
+    max_echo_size: u64,
+    /// Largest single file accepted by uploads to /files, in bytes
+    #[arg(long, default_value_t = 10 * 1024 * 1024)]
+    max_upload_file_size: u64,
+    /// Largest multipart body accepted by uploads to /files, in bytes
+    #[arg(long, default_value_t = 50 * 1024 * 1024)]
+    max_upload_size: u64,
+    /// Requests per minute each client may make to /delay and /divide
+    #[arg(long)]
+    rate_limit: Option<std::num::NonZeroU32>,
+    /// Requests per second each client may make to any other route
+    #[arg(long)]
+    global_rate_limit: Option<std::num::NonZeroU32>,
+    /// What identifies a client for rate limits: `ip`, `header:<name>` or `query:<name>`
+    #[arg(long, default_value = "ip")]
+    rate_limit_key: String,
+    /// Proxy address or CIDR range whose forwarding headers name the real client
+    #[arg(long)]
+    trusted_proxy: Vec<client_ip::IpCidr>,
+    /// File of `<path prefix> <allow|deny> <cidr>` lines, reloaded when it changes
+    #[arg(long)]
+    ip_rules: Option<String>,
+    /// Address or CIDR range allowed to reach /stats and the metrics path; others get 403
+    #[arg(long)]
+    admin_allow: Vec<client_ip::IpCidr>,
+    /// Address or CIDR range refused on every route
+    #[arg(long)]
+    deny_ip: Vec<client_ip::IpCidr>,
+    /// Access log layout: `common`, `combined` or an Apache-style template
+    #[arg(long, default_value = "common")]
+    access_log_format: AccessLogFormat,
+    /// Access log destination: `stdout`, `syslog` or a file path
+    #[arg(long, default_value = "stdout")]
+    access_log: LogSink,
+    /// Path serving Prometheus metrics
+    #[arg(long, default_value = "/metrics")]
+    metrics_path: String,
+    /// OTLP/HTTP collector receiving spans as JSON, e.g. `http://localhost:4318/v1/traces`
+    #[arg(long, conflicts_with = "trace_file")]
+    otlp_endpoint: Option<String>,
+    /// File receiving one JSON span per line
+    #[arg(long)]
+    trace_file: Option<String>,
+    /// Generated request id format: `uuid` or `ulid`
+    #[arg(long, default_value = "uuid")]
+    request_id_format: RequestIdFormat,
+    /// Header carrying the request id in both directions
+    #[arg(long, default_value = "X-Request-Id")]
+    request_id_header: String,
+    /// Show panic messages, locations and backtraces in 500 responses
+    #[arg(long)]
+    dev: bool,
+    /// Error body format: `problem+json` (RFC 9457) or `text`
+    #[arg(long, default_value = "problem+json")]
+    error_format: ErrorFormat,
+    /// Least severe server log level: `trace`, `debug`, `info`, `warn` or `error`
+    #[arg(long, env = "LOG_LEVEL", default_value = "info")]
+    log_level: logger::Level,
+    /// Server log destination: `stdout`, `syslog` or a file path
+    #[arg(long, default_value = "stdout")]
+    log: LogSink,
 }
 
 extern crate strum;
 #[macro_use]
 extern crate strum_macros;
 
+/// Route limits taken from the command line, shared with handlers as state.
+struct Limits {
+    max_delay: u64,
+    max_echo_size: u64,
+}
+
+/// Plain-text notes kept in memory behind `/notes/{name}`.
+#[derive(Default)]
+struct Notes {
+    entries: Mutex<HashMap<String, String>>,
+}
+
+#[derive(Deserialize)]
+struct NoteParams {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct DelayParams {
+    #[serde(default = "DelayParams::default_sec")]
+    sec: u64,
+}
+
+impl DelayParams {
+    fn default_sec() -> u64 {
+        1
+    }
+}
+
+#[derive(Deserialize)]
+struct DivideParams {
+    #[serde(default)]
+    a: i32,
+    #[serde(default = "DivideParams::default_b")]
+    b: i32,
+}
+
+impl DivideParams {
+    fn default_b() -> i32 {
+        1
+    }
+
+    fn divide(&self) -> Result<String, HttpError> {
+        let res = self
+            .a
+            .checked_div(self.b)
+            .ok_or_else(|| HttpError::bad_request("Division by zero or overflow"))?;
+        Ok(res.to_string())
+    }
+}
+
+#[derive(Deserialize)]
+struct StatusParams {
+    code: u16,
+}
+
+#[derive(Deserialize)]
+struct ReasonParams {
+    reason: Option<String>,
+}
+
+impl StatusParams {
+    fn respond(&self, reason: Option<&str>) -> Result<HttpResponse, HttpError> {
+        let status = HttpStatusCode::from_u16(self.code)
+            .ok_or_else(|| HttpError::bad_request("Status code must be between 100 and 599"))?;
+        Ok(match reason {
+            Some(reason) => HttpResponse::new_with_reason(status, reason),
+            None => HttpResponse::new(status),
+        })
+    }
+}
+
+/// `http://localhost` or `http://127.0.0.1` on any port, for frontends under development.
+fn is_local_origin(origin: &str) -> bool {
+    origin
+        .strip_prefix("http://")
+        .and_then(|authority| authority.split(':').next())
+        .is_some_and(|host| host.eq_ignore_ascii_case("localhost") || host == "127.0.0.1")
+}
+
+/// One `name=value` line per field of a urlencoded or multipart form body, or per
+/// value of `?field=name`. File fields show the file name and size instead.
+fn echo_form(req: &mut HttpRequest) -> std::io::Result<String> {
+    let mut lines = Vec::new();
+    match req.get_header("Content-Type").and_then(|t| form::boundary(t)) {
+        Some(boundary) => {
+            let mut multipart = req.content.multipart(&boundary, MultipartLimits::default())?;
+            while let Some(part) = multipart.next_part()? {
+                let name = part.name.clone().unwrap_or_default();
+                if part.is_file() {
+                    let size = multipart.read_to(&mut std::io::sink())?;
+                    lines.push(format!("{}={} ({} bytes)", name, part.filename.unwrap_or_default(), size));
+                } else {
+                    lines.push(format!("{}={}", name, multipart.read_string()?));
+                }
+            }
+        }
+        None => {
+            let form = req.content.to_form()?;
+            match req.query_params.get("field") {
+                Some(name) => lines.extend(form.get_all(name).iter().map(|v| format!("{}={}", name, v))),
+                None => lines.extend(form.iter().map(|(k, v)| format!("{}={}", k, v))),
+            }
+        }
+    }
+    Ok(lines.join("\n"))
+}
+
 fn main() {
+    let args = Args::parse();
     let mut server = http_server::HttpServer::new();
+    server.add_state(Limits {
+        max_delay: args.max_delay,
+        max_echo_size: args.max_echo_size,
+    });
+
+    server.get("/hello", |_: &mut HttpRequest, _: &HttpContext| "Hello, World!");
 
-    server.get("/hello", |_: &mut HttpRequest, _: &HttpContext| {
-        HttpResponse::new(HttpStatusCode::OK).with_body("Hello, World!")
+    server.get("/", |_: &mut HttpRequest, _: &HttpContext| HttpStatusCode::OK);
+
+    server.get("/echo/{message}", |_: &mut HttpRequest, context: &HttpContext| {
+        context.get_path_param("message").cloned().unwrap_or_default()
     });
 
-    server.get("/", |_: &mut HttpRequest, _: &HttpContext| {
-        HttpResponse::new(HttpStatusCode::OK)
+    server.get("/user-agent", |user_agent: Option<Header<UserAgent>>| {
+        user_agent.map(|Header(UserAgent(agent))| agent).unwrap_or_default()
     });
 
-    server.get(
-        "/echo/{message}",
-        |_: &mut HttpRequest, context: &HttpContext| {
-            HttpResponse::new(HttpStatusCode::OK).with_body(
-                context
-                    .get_path_param("message")
-                    .unwrap_or(&"".to_string())
-                    .as_str(),
-            )
-        },
-    );
+    server.get("/delay", |limits: State<Limits>, Query(params): Query<DelayParams>| {
+        if params.sec > limits.max_delay {
+            return Err(HttpError::bad_request(&format!("Delay is capped at {}s", limits.max_delay)));
+        }
+        let mut span = trace::Span::child("sleep");
+        span.set_attribute("sleep.seconds", params.sec);
+        std::thread::sleep(std::time::Duration::from_secs(params.sec));
+        Ok("Delayed response")
+    });
 
-    server.get("/user-agent", |req: &mut HttpRequest, _: &HttpContext| {
-        HttpResponse::new(HttpStatusCode::OK).with_body(
-            req.headers
-                .get("User-Agent")
-                .unwrap_or(&"".to_string())
-                .as_str(),
-        )
+    server.get("/panic", |_: &mut HttpRequest, _: &HttpContext| -> HttpResponse {
+        panic!("Intentional panic for testing");
     });
 
-    server.get("/delay", |req: &mut HttpRequest, _: &HttpContext| {
-        let delay_seconds: u64 = req
-            .query_params
-            .get("sec")
-            .and_then(|s| s.parse().ok())
-            .unwrap_or(1);
-        std::thread::sleep(std::time::Duration::from_secs(delay_seconds));
-        HttpResponse::new(HttpStatusCode::OK).with_body("Delayed response")
+    server.get("/divide", |Query(params): Query<DivideParams>| params.divide());
+    server.get("/divide/{a}/{b}", |Path(params): Path<DivideParams>| params.divide());
+    server.post("/divide", |Form(params): Form<DivideParams>| params.divide());
+
+    // Replies with any status, e.g. `/status/599?reason=Network%20Connect%20Timeout`
+    server.get("/status/{code}", |Path(params): Path<StatusParams>, Query(query): Query<ReasonParams>| {
+        params.respond(query.reason.as_deref())
     });
 
-    server.get("/panic", |_: &mut HttpRequest, _: &HttpContext| {
-        panic!("Intentional panic for testing");
+    // Checked against Content-Length so oversized bodies are refused unread
+    server.post(
+        "/echo-body",
+        |limits: State<Limits>, length: Option<Header<ContentLength>>, BodyBytes(body): BodyBytes| {
+            if length.is_some_and(|Header(ContentLength(n))| n > limits.max_echo_size) {
+                return Err(HttpError::new(HttpStatusCode::ContentTooLarge, "Body too large to echo"));
+            }
+            Ok(String::from_utf8_lossy(&body).into_owned())
+        },
+    );
+    server.post("/echo-form", |req: &mut HttpRequest, _: &HttpContext| {
+        echo_form(req).map_err(|e| match e.kind() {
+            std::io::ErrorKind::FileTooLarge => HttpError::new(HttpStatusCode::ContentTooLarge, &e.to_string()),
+            _ => HttpError::bad_request(&format!("Invalid form body: {}", e)),
+        })
     });
+    server.post("/echo-json", |Json(value): Json<serde_json::Value>| Json(value));
 
-    server.get("/divide", |req: &mut HttpRequest, _: &HttpContext| {
-        let a = req
-            .query_params
-            .get("a")
-            .and_then(|s| s.parse::<i32>().ok())
-            .unwrap_or(0);
-        let b = req
-            .query_params
-            .get("b")
-            .and_then(|s| s.parse::<i32>().ok())
-            .unwrap_or(1);
-        let res = a / b;
-        HttpResponse::new(HttpStatusCode::OK).with_body(&res.to_string())
+    server.add_state(Notes::default());
+    server.get("/notes/{name}", |notes: State<Notes>, Path(params): Path<NoteParams>| {
+        notes.entries.lock().unwrap().get(&params.name).cloned()
+    });
+    server.put("/notes/{name}", |notes: State<Notes>, Path(params): Path<NoteParams>, BodyBytes(body): BodyBytes| {
+        let note = String::from_utf8_lossy(&body).into_owned();
+        match notes.entries.lock().unwrap().insert(params.name, note) {
+            Some(_) => HttpStatusCode::NoContent,
+            None => HttpStatusCode::Created,
+        }
+    });
+    server.patch("/notes/{name}", |notes: State<Notes>, Path(params): Path<NoteParams>, BodyBytes(body): BodyBytes| {
+        let mut notes = notes.entries.lock().unwrap();
+        let note = notes.get_mut(&params.name)?;
+        note.push_str(&String::from_utf8_lossy(&body));
+        Some(HttpStatusCode::NoContent)
+    });
+    server.delete("/notes/{name}", |notes: State<Notes>, Path(params): Path<NoteParams>| {
+        notes.entries.lock().unwrap().remove(&params.name).map(|_| HttpStatusCode::NoContent)
+    });
+    server.options("/notes/{name}", |_: &mut HttpRequest, _: &HttpContext| {
+        HttpResponse::new(HttpStatusCode::NoContent).with_header("Allow", "GET, HEAD, PUT, PATCH, DELETE, OPTIONS")
     });
 
-    server.post("/echo-body", |req: &mut HttpRequest, _: &HttpContext| {
-        HttpResponse::new(HttpStatusCode::OK)
-            .with_body(req.content.to_string().unwrap_or("".to_string()).as_str())
+    server.any("/method", |req: &mut HttpRequest, _: &HttpContext| req.method.to_string());
+
+    server.get("/whoami", |req: &mut HttpRequest, _: &HttpContext| {
+        match req.get_extension::<JwtClaims>().and_then(|c| c.0["sub"].as_str()) {
+            Some(sub) => sub.to_string(),
+            None => "anonymous".to_string(),
+        }
     });
 
-    server.use_middleware(Box::new(StatisticMiddleware::new("/stats")));
-    server.use_middleware(Box::new(PanicMiddleware::new()));
-    server.use_middleware(Box::new(LoggingMiddleware::new()));
+    logger::init(args.log_level, args.log.clone()).expect("Failed to open server log");
+    http_error::set_renderer(args.error_format.renderer());
+    if let Some(url) = &args.otlp_endpoint {
+        trace::init(trace::SpanExport::Otlp(url.clone())).expect("Invalid OTLP endpoint");
+    } else if let Some(path) = &args.trace_file {
+        trace::init(trace::SpanExport::File(path.clone())).expect("Failed to open trace file");
+    }
+
+    let statistic = StatisticMiddleware::new();
+    // Behind auth with everything else, while the counting and timing happen further out
+    server.use_middleware(Box::new(statistic.endpoint("/stats")));
+    server.use_middleware(Box::new(MetricsMiddleware::endpoint(&args.metrics_path)));
     server.use_middleware(Box::new(EncodingMiddleware::new()));
 
-    let args = Args::parse();
-    server.use_middleware(Box::new(StaticFilesMiddleware::new(
-        "/files",
-        &args.directory,
+    server.set_keep_alive_timeout(std::time::Duration::from_secs(args.keep_alive_timeout));
+    server.set_max_requests_per_connection(args.max_requests_per_connection);
+    server.set_max_drain_size(args.max_drain_size);
+    server.set_trusted_proxies(args.trusted_proxy.clone());
+    server.use_middleware(Box::new(
+        StaticFilesMiddleware::new("/files", &args.directory).with_upload_limits(MultipartLimits {
+            max_part_size: args.max_upload_file_size,
+            max_total_size: args.max_upload_size,
+        }),
+    ));
+    // Outside StaticFiles so uploads are stored decoded
+    server.use_middleware(Box::new(RequestDecompressionMiddleware::new(
+        10 * 1024 * 1024,
     )));
 
+    if args.htpasswd.is_some() || !args.api_token.is_empty() {
+        let mut auth = AuthMiddleware::new("admin")
+            .protect("/stats", &[])
+            .protect(&args.metrics_path, &[])
+            .protect("/files", &[HttpMethod::POST]);
+        if let Some(path) = &args.htpasswd {
+            let store = HtpasswdStore::from_file(path).expect("Failed to read htpasswd file");
+            auth = auth.with_basic(Box::new(store));
+        }
+        if !args.api_token.is_empty() {
+            let tokens = args.api_token.clone();
+            auth = auth.with_bearer(move |token| {
+                tokens.iter().any(|t| t == token).then(|| "api".to_string())
+            });
+        }
+        server.use_middleware(Box::new(auth));
+    }
+
+    let jwt_keys = [&args.jwt_secret, &args.jwt_rs256_key, &args.jwt_es256_key, &args.jwks_file];
+    if jwt_keys.iter().any(|key| key.is_some()) {
+        let scopes: Vec<&str> = args.jwt_scope.iter().map(String::as_str).collect();
+        let mut jwt = JwtMiddleware::new()
+            .protect("/whoami", &scopes)
+            .with_leeway(args.jwt_leeway);
+        if let Some(secret) = &args.jwt_secret {
+            jwt = jwt.with_hs256_secret(secret.as_bytes());
+        }
+        if let Some(path) = &args.jwt_rs256_key {
+            let pem = std::fs::read(path).expect("Failed to read RS256 key");
+            jwt = jwt.with_rs256_pem(&pem).expect("Invalid RS256 key");
+        }
+        if let Some(path) = &args.jwt_es256_key {
+            let pem = std::fs::read(path).expect("Failed to read ES256 key");
+            jwt = jwt.with_es256_pem(&pem).expect("Invalid ES256 key");
+        }
+        if let Some(path) = &args.jwks_file {
+            jwt = jwt.with_jwks_file(path).expect("Failed to read JWKS file");
+        }
+        if let Some(issuer) = &args.jwt_issuer {
+            jwt = jwt.with_issuer(issuer);
+        }
+        if let Some(audience) = &args.jwt_audience {
+            jwt = jwt.with_audience(audience);
+        }
+        server.use_middleware(Box::new(jwt));
+    }
+
+    if let Some(path) = &args.ip_rules {
+        let filter = IpFilterMiddleware::from_file(path).expect("Failed to load IP rules");
+        server.use_middleware(Box::new(filter));
+    }
+    if !args.admin_allow.is_empty() || !args.deny_ip.is_empty() {
+        let mut rules = Vec::new();
+        if !args.admin_allow.is_empty() {
+            for prefix in ["/stats", args.metrics_path.as_str()] {
+                let rule = args.admin_allow.iter().fold(IpFilterRule::new(prefix), |rule, cidr| rule.allow(*cidr));
+                rules.push(rule);
+            }
+        }
+        if !args.deny_ip.is_empty() {
+            rules.push(args.deny_ip.iter().fold(IpFilterRule::new("/"), |rule, cidr| rule.deny(*cidr)));
+        }
+        server.use_middleware(Box::new(IpFilterMiddleware::new(rules)));
+    }
+
+    if args.rate_limit.is_some() || args.global_rate_limit.is_some() {
+        let key: RateLimitKey = args.rate_limit_key.parse().expect("Failed to parse rate limit key");
+        let mut limiter = RateLimitMiddleware::new(key);
+        if let Some(requests) = args.rate_limit {
+            limiter = limiter
+                .with_route_limit("/delay", RateLimit::per_minute(requests))
+                .with_route_limit("/divide", RateLimit::per_minute(requests));
+        }
+        if let Some(requests) = args.global_rate_limit {
+            limiter = limiter.with_default_limit(RateLimit::per_second(requests));
+        }
+        server.use_middleware(Box::new(limiter));
+    }
+
+    // Outside static files, auth and rate limiting, so preflights never need
+    // credentials and every response can carry the CORS headers
+    if !args.cors_origin.is_empty() || !args.cors_origin_regex.is_empty() || args.cors_allow_localhost {
+        let mut cors = args.cors_origin.iter().fold(CorsMiddleware::new(), |cors, origin| {
+            cors.with_allowed_origin(AllowedOrigin::parse(origin))
+        });
+        for re in &args.cors_origin_regex {
+            cors = cors.with_allowed_origin(AllowedOrigin::Regex(re.clone()));
+        }
+        if args.cors_allow_localhost {
+            cors = cors.with_allowed_origin(AllowedOrigin::Predicate(Box::new(is_local_origin)));
+        }
+        if !args.cors_method.is_empty() {
+            cors = cors.with_allowed_methods(args.cors_method.clone());
+        }
+        let headers: Vec<&str> = args.cors_header.iter().map(String::as_str).collect();
+        let exposed_headers: Vec<&str> = args.cors_expose_header.iter().map(String::as_str).collect();
+        cors = cors
+            .with_allowed_headers(&headers)
+            .with_exposed_headers(&exposed_headers)
+            .with_credentials(args.cors_credentials);
+        if let Some(seconds) = args.cors_max_age {
+            cors = cors.with_max_age(seconds);
+        }
+        server.use_middleware(Box::new(cors));
+    }
+
+    // Inside only the middlewares that observe requests, so a panic anywhere else
+    // still becomes a 500 that is counted, timed and logged
+    server.use_middleware(Box::new(PanicMiddleware::new().with_dev_mode(args.dev)));
+    server.use_middleware(Box::new(statistic));
+
+    // Outside auth and rate limiting so rejected requests are timed too
+    server.use_middleware(Box::new(MetricsMiddleware::new()));
+
+    // Outside everything but tracing and request ids, so rejected requests are
+    // logged inside their span and byte counts are post-compression
+    let logging = LoggingMiddleware::with_format(args.access_log_format, args.access_log)
+        .expect("Failed to open access log");
+    server.use_middleware(Box::new(logging));
+    server.use_middleware(Box::new(TracingMiddleware::new()));
+    // Outside everything else so the id is in every log line and error body
+    server.use_middleware(Box::new(
+        RequestIdMiddleware::new()
+            .with_format(args.request_id_format)
+            .with_header(&args.request_id_header),
+    ));
+
     server.run("127.0.0.1:4221");
 }
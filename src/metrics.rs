@@ -0,0 +1,229 @@
+use crate::http_response::HttpStatusCode;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, RwLock};
+use std::time::Duration;
+
+/// Upper bounds in seconds, the Prometheus client library defaults.
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// The process-wide registry shared by the server and middlewares.
+pub fn global() -> &'static Metrics {
+    &METRICS
+}
+
+#[derive(Default)]
+struct Histogram {
+    // Per-bucket counts; the last slot is +Inf. Made cumulative when rendered.
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let index = BUCKETS
+            .iter()
+            .position(|le| seconds <= *le)
+            .unwrap_or(BUCKETS.len());
+        self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct SeriesKey {
+    route: String,
+    method: String,
+    status_class: String,
+}
+
+/// Decrements its gauge when dropped, so panics and early returns are accounted for.
+pub struct GaugeGuard<'a>(&'a AtomicI64);
+
+impl<'a> GaugeGuard<'a> {
+    fn new(gauge: &'a AtomicI64) -> Self {
+        gauge.fetch_add(1, Ordering::Relaxed);
+        GaugeGuard(gauge)
+    }
+}
+
+impl Drop for GaugeGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct Metrics {
+    // Written only the first time a series shows up; observations are atomic
+    durations: RwLock<HashMap<SeriesKey, Arc<Histogram>>>,
+    in_flight: AtomicI64,
+    open_connections: AtomicI64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    uncompressed_bytes: AtomicU64,
+    compressed_bytes: AtomicU64,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Metrics {
+            durations: RwLock::new(HashMap::new()),
+            in_flight: AtomicI64::new(0),
+            open_connections: AtomicI64::new(0),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            uncompressed_bytes: AtomicU64::new(0),
+            compressed_bytes: AtomicU64::new(0),
+        }
+    }
+
+    pub fn connection_opened(&self) -> GaugeGuard<'_> {
+        GaugeGuard::new(&self.open_connections)
+    }
+
+    pub fn request_started(&self) -> GaugeGuard<'_> {
+        GaugeGuard::new(&self.in_flight)
+    }
+
+    pub fn add_bytes_received(&self, bytes: usize) {
+        self.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Body sizes before and after a response was content-encoded.
+    pub fn record_compression(&self, uncompressed: usize, compressed: usize) {
+        self.uncompressed_bytes
+            .fetch_add(uncompressed as u64, Ordering::Relaxed);
+        self.compressed_bytes
+            .fetch_add(compressed as u64, Ordering::Relaxed);
+    }
+
+    /// `route` is the matched pattern such as `/echo/{message}`, never the raw path.
+    pub fn observe_request(
+        &self,
+        route: &str,
+        method: &str,
+        status: HttpStatusCode,
+        duration: Duration,
+    ) {
+        let key = SeriesKey {
+            route: route.to_string(),
+            method: method.to_string(),
+            status_class: format!("{}xx", status.as_u16() / 100),
+        };
+        let existing = self.durations.read().unwrap().get(&key).cloned();
+        let histogram = match existing {
+            Some(histogram) => histogram,
+            None => Arc::clone(self.durations.write().unwrap().entry(key).or_default()),
+        };
+        histogram.observe(duration);
+    }
+
+    /// Prometheus text exposition format, version 0.0.4.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP http_request_duration_seconds Time spent handling requests.\n");
+        out.push_str("# TYPE http_request_duration_seconds histogram\n");
+        let mut series: Vec<(SeriesKey, Arc<Histogram>)> = self
+            .durations
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(k, h)| (k.clone(), Arc::clone(h)))
+            .collect();
+        series.sort_by(|a, b| a.0.cmp(&b.0));
+        for (key, histogram) in series {
+            let labels = format!(
+                "route=\"{}\",method=\"{}\",status=\"{}\"",
+                escape_label(&key.route),
+                escape_label(&key.method),
+                key.status_class
+            );
+            let mut cumulative = 0;
+            for (i, bucket) in histogram.buckets.iter().enumerate() {
+                cumulative += bucket.load(Ordering::Relaxed);
+                let le = BUCKETS.get(i).map(|le| le.to_string()).unwrap_or("+Inf".to_string());
+                let _ = writeln!(out, "http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}", labels, le, cumulative);
+            }
+            let sum = histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+            let _ = writeln!(out, "http_request_duration_seconds_sum{{{}}} {}", labels, sum);
+            let _ = writeln!(out, "http_request_duration_seconds_count{{{}}} {}", labels, histogram.count.load(Ordering::Relaxed));
+        }
+
+        let uncompressed = self.uncompressed_bytes.load(Ordering::Relaxed);
+        let compressed = self.compressed_bytes.load(Ordering::Relaxed);
+        let ratio = if compressed == 0 { 1.0 } else { uncompressed as f64 / compressed as f64 };
+        let scalars: [(&str, &str, &str, String); 7] = [
+            ("http_requests_in_flight", "gauge", "Requests currently being handled.", self.in_flight.load(Ordering::Relaxed).to_string()),
+            ("http_open_connections", "gauge", "Client connections currently open.", self.open_connections.load(Ordering::Relaxed).to_string()),
+            ("http_received_bytes_total", "counter", "Bytes read from clients.", self.bytes_received.load(Ordering::Relaxed).to_string()),
+            ("http_sent_bytes_total", "counter", "Bytes written to clients.", self.bytes_sent.load(Ordering::Relaxed).to_string()),
+            ("http_response_uncompressed_bytes_total", "counter", "Response body bytes before content encoding.", uncompressed.to_string()),
+            ("http_response_compressed_bytes_total", "counter", "Response body bytes after content encoding.", compressed.to_string()),
+            ("http_response_compression_ratio", "gauge", "Uncompressed over compressed response body bytes.", ratio.to_string()),
+        ];
+        for (name, kind, help, value) in scalars {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}\n{} {}", name, help, name, kind, name, value);
+        }
+        out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_should_be_cumulative() {
+        let metrics = Metrics::new();
+        for millis in [3, 40, 40, 20_000] {
+            metrics.observe_request("/echo/{message}", "GET", HttpStatusCode::OK, Duration::from_millis(millis));
+        }
+        metrics.observe_request("/echo/{message}", "GET", HttpStatusCode::NotFound, Duration::from_millis(1));
+        let text = metrics.render();
+        let labels = "route=\"/echo/{message}\",method=\"GET\",status=\"2xx\"";
+        assert!(text.contains(&format!("http_request_duration_seconds_bucket{{{},le=\"0.005\"}} 1\n", labels)));
+        assert!(text.contains(&format!("http_request_duration_seconds_bucket{{{},le=\"0.05\"}} 3\n", labels)));
+        assert!(text.contains(&format!("http_request_duration_seconds_bucket{{{},le=\"10\"}} 3\n", labels)));
+        assert!(text.contains(&format!("http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} 4\n", labels)));
+        assert!(text.contains(&format!("http_request_duration_seconds_count{{{}}} 4\n", labels)));
+        assert!(text.contains("status=\"4xx\""));
+    }
+
+    #[test]
+    fn gauges_should_follow_guards() {
+        let metrics = Metrics::new();
+        {
+            let _connection = metrics.connection_opened();
+            let _request = metrics.request_started();
+            assert!(metrics.render().contains("http_requests_in_flight 1\n"));
+        }
+        metrics.record_compression(1000, 250);
+        let text = metrics.render();
+        assert!(text.contains("http_requests_in_flight 0\n"));
+        assert!(text.contains("http_open_connections 0\n"));
+        assert!(text.contains("http_response_compression_ratio 4\n"));
+    }
+
+    #[test]
+    fn label_values_should_be_escaped() {
+        assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
@@ -1,15 +1,33 @@
+mod auth_middleware;
+mod cors_middleware;
 mod encoding_middleware;
 mod http_middleware;
+mod ip_filter_middleware;
+mod jwt_middleware;
 mod logging_middleware;
+mod metrics_middleware;
 mod panic_middleware;
+mod rate_limit_middleware;
+mod request_decompression_middleware;
+mod request_id_middleware;
 mod routing_middleware;
 mod static_files_middleware;
+mod tracing_middleware;
 mod statistic_middleware;
 
+pub use auth_middleware::{AuthMiddleware, HtpasswdStore, Principal};
+pub use cors_middleware::{AllowedOrigin, CorsMiddleware};
 pub use encoding_middleware::EncodingMiddleware;
 pub use http_middleware::HttpMiddleware;
-pub use logging_middleware::LoggingMiddleware;
+pub use ip_filter_middleware::{IpFilterMiddleware, IpFilterRule};
+pub use jwt_middleware::{JwtClaims, JwtMiddleware};
+pub use logging_middleware::{AccessLogFormat, LoggingMiddleware};
+pub use metrics_middleware::MetricsMiddleware;
 pub use panic_middleware::PanicMiddleware;
-pub use routing_middleware::RoutingMiddleware;
+pub use rate_limit_middleware::{RateLimit, RateLimitKey, RateLimitMiddleware};
+pub use request_decompression_middleware::RequestDecompressionMiddleware;
+pub use request_id_middleware::{RequestId, RequestIdFormat, RequestIdMiddleware};
+pub use routing_middleware::{MatchedRoute, RoutingMiddleware};
 pub use static_files_middleware::StaticFilesMiddleware;
 pub use statistic_middleware::StatisticMiddleware;
+pub use tracing_middleware::TracingMiddleware;
@@ -0,0 +1,302 @@
+use crate::http_error::HttpError;
+use crate::http_request::{HttpMethod, HttpRequest};
+use crate::http_response::{HttpResponse, HttpStatusCode, IntoResponse};
+use crate::middlewares::http_middleware::HttpMiddleware;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthScheme {
+    Basic,
+    Bearer,
+}
+
+/// The authenticated caller, available to handlers via `request.get_extension::<Principal>()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Principal {
+    pub name: String,
+    pub scheme: AuthScheme,
+}
+
+pub trait CredentialStore {
+    fn verify(&self, user: &str, password: &str) -> bool;
+}
+
+/// `user:hash` lines as written by `htpasswd -B`. Besides bcrypt (`$2a$`, `$2b$`,
+/// `$2y$`) it accepts `{SHA256}` followed by the base64 SHA-256 of the password.
+pub struct HtpasswdStore {
+    entries: HashMap<String, String>,
+}
+
+impl HtpasswdStore {
+    pub fn from_file(path: &str) -> std::io::Result<Self> {
+        Ok(Self::parse(&std::fs::read_to_string(path)?))
+    }
+
+    pub fn parse(contents: &str) -> Self {
+        let entries = contents
+            .lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .filter_map(|l| l.split_once(':'))
+            .map(|(user, hash)| (user.to_string(), hash.to_string()))
+            .collect();
+        HtpasswdStore { entries }
+    }
+}
+
+impl CredentialStore for HtpasswdStore {
+    fn verify(&self, user: &str, password: &str) -> bool {
+        let Some(hash) = self.entries.get(user) else {
+            return false;
+        };
+        if let Some(expected) = hash.strip_prefix("{SHA256}") {
+            let actual = STANDARD.encode(Sha256::digest(password.as_bytes()));
+            constant_time_eq(actual.as_bytes(), expected.as_bytes())
+        } else if hash.starts_with("$2") {
+            bcrypt::verify(password, hash).unwrap_or(false)
+        } else {
+            false
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+struct ProtectedScope {
+    prefix: String,
+    methods: Vec<HttpMethod>,
+}
+
+impl ProtectedScope {
+    fn matches(&self, method: &HttpMethod, path: &str) -> bool {
+        let prefix = self.prefix.trim_end_matches('/');
+        let in_prefix = match path.strip_prefix(prefix) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        };
+        in_prefix && (self.methods.is_empty() || self.methods.contains(method))
+    }
+}
+
+type TokenValidator = Box<dyn Fn(&str) -> Option<String> + Send + Sync>;
+
+pub struct AuthMiddleware {
+    realm: String,
+    credentials: Option<Box<dyn CredentialStore + Send + Sync>>,
+    token_validator: Option<TokenValidator>,
+    scopes: Vec<ProtectedScope>,
+}
+
+impl AuthMiddleware {
+    pub fn new(realm: &str) -> Self {
+        AuthMiddleware {
+            realm: realm.to_string(),
+            credentials: None,
+            token_validator: None,
+            scopes: Vec::new(),
+        }
+    }
+
+    /// Accepts `Authorization: Basic` checked against `store`.
+    pub fn with_basic(mut self, store: Box<dyn CredentialStore + Send + Sync>) -> Self {
+        self.credentials = Some(store);
+        self
+    }
+
+    /// Accepts `Authorization: Bearer`; `validator` maps a valid token to the principal name.
+    pub fn with_bearer(
+        mut self,
+        validator: impl Fn(&str) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.token_validator = Some(Box::new(validator));
+        self
+    }
+
+    /// Requires authentication under `prefix` for `methods`, or for every method if empty.
+    pub fn protect(mut self, prefix: &str, methods: &[HttpMethod]) -> Self {
+        self.scopes.push(ProtectedScope {
+            prefix: prefix.to_string(),
+            methods: methods.to_vec(),
+        });
+        self
+    }
+
+    fn authenticate(&self, authorization: &str) -> Result<Principal, Option<&'static str>> {
+        let (scheme, credentials) = authorization.split_once(' ').unwrap_or((authorization, ""));
+        let credentials = credentials.trim();
+        if scheme.eq_ignore_ascii_case("Basic")
+            && let Some(store) = &self.credentials
+        {
+            let decoded = STANDARD
+                .decode(credentials)
+                .ok()
+                .and_then(|d| String::from_utf8(d).ok())
+                .ok_or(None)?;
+            let (user, password) = decoded.split_once(':').ok_or(None)?;
+            if store.verify(user, password) {
+                return Ok(Principal {
+                    name: user.to_string(),
+                    scheme: AuthScheme::Basic,
+                });
+            }
+            return Err(None);
+        }
+        if scheme.eq_ignore_ascii_case("Bearer")
+            && let Some(validator) = &self.token_validator
+        {
+            return match validator(credentials) {
+                Some(name) => Ok(Principal {
+                    name,
+                    scheme: AuthScheme::Bearer,
+                }),
+                None => Err(Some("invalid_token")),
+            };
+        }
+        Err(None)
+    }
+
+    fn challenge(&self, bearer_error: Option<&str>) -> HttpResponse {
+        let mut challenges = Vec::new();
+        if self.credentials.is_some() {
+            challenges.push(format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm));
+        }
+        if self.token_validator.is_some() {
+            match bearer_error {
+                Some(error) => challenges.push(format!(
+                    "Bearer realm=\"{}\", error=\"{}\"",
+                    self.realm, error
+                )),
+                None => challenges.push(format!("Bearer realm=\"{}\"", self.realm)),
+            }
+        }
+        HttpError::new(HttpStatusCode::Unauthorized, "Missing or invalid credentials")
+            .into_response()
+            .with_header("WWW-Authenticate", &challenges.join(", "))
+    }
+}
+
+impl HttpMiddleware for AuthMiddleware {
+    fn handle(
+        &self,
+        request: &mut HttpRequest,
+        next: &dyn Fn(&mut HttpRequest) -> HttpResponse,
+    ) -> HttpResponse {
+        if !self
+            .scopes
+            .iter()
+            .any(|s| s.matches(&request.method, &request.path))
+        {
+            return next(request);
+        }
+        // An outer AuthMiddleware already vouched for the caller
+        if request.get_extension::<Principal>().is_some() {
+            return next(request);
+        }
+        let Some(authorization) = request.get_header("Authorization").cloned() else {
+            return self.challenge(None);
+        };
+        match self.authenticate(&authorization) {
+            Ok(principal) => {
+                request.insert_extension(principal);
+                next(request)
+            }
+            Err(bearer_error) => self.challenge(bearer_error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn handle(auth: &AuthMiddleware, raw: &str) -> HttpResponse {
+        let mut reader = Cursor::new(raw.as_bytes());
+        let mut request = HttpRequest::from_reader(&mut reader).unwrap();
+        auth.handle(&mut request, &|req: &mut HttpRequest| {
+            let name = req.get_extension::<Principal>().map(|p| p.name.clone());
+            HttpResponse::new(HttpStatusCode::OK).with_body(&name.unwrap_or_default())
+        })
+    }
+
+    #[test]
+    fn htpasswd_should_verify_bcrypt_and_sha256() {
+        let bcrypt_hash = bcrypt::hash("secret", 4).unwrap();
+        let sha_hash = STANDARD.encode(Sha256::digest(b"hunter2"));
+        let store = HtpasswdStore::parse(&format!(
+            "# users\nalice:{}\nbob:{{SHA256}}{}\n",
+            bcrypt_hash, sha_hash
+        ));
+        assert!(store.verify("alice", "secret"));
+        assert!(!store.verify("alice", "wrong"));
+        assert!(store.verify("bob", "hunter2"));
+        assert!(!store.verify("carol", "hunter2"));
+    }
+
+    #[test]
+    fn protected_path_should_require_credentials() {
+        let sha_hash = STANDARD.encode(Sha256::digest(b"hunter2"));
+        let auth = AuthMiddleware::new("admin")
+            .with_basic(Box::new(HtpasswdStore::parse(&format!("bob:{{SHA256}}{}", sha_hash))))
+            .with_bearer(|token| (token == "t0ken").then(|| "service".to_string()))
+            .protect("/stats", &[])
+            .protect("/files", &[HttpMethod::POST]);
+
+        let response = handle(&auth, "GET /stats HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), HttpStatusCode::Unauthorized);
+        assert_eq!(response.get_header("Content-Type").unwrap(), "application/problem+json");
+        assert_eq!(
+            response.get_header("WWW-Authenticate").unwrap(),
+            "Basic realm=\"admin\", charset=\"UTF-8\", Bearer realm=\"admin\""
+        );
+
+        let basic = STANDARD.encode("bob:hunter2");
+        let response = handle(&auth, &format!("GET /stats HTTP/1.1\r\nAuthorization: Basic {}\r\n\r\n", basic));
+        assert_eq!(response.status_code(), HttpStatusCode::OK);
+        assert_eq!(response.get_body().unwrap(), b"bob");
+
+        let response = handle(&auth, "POST /files/a HTTP/1.1\r\nAuthorization: Bearer t0ken\r\n\r\n");
+        assert_eq!(response.get_body().unwrap(), b"service");
+
+        let response = handle(&auth, "POST /files/a HTTP/1.1\r\nAuthorization: Bearer nope\r\n\r\n");
+        assert!(response.get_header("WWW-Authenticate").unwrap().contains("error=\"invalid_token\""));
+
+        let response = handle(&auth, "GET /files/a HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), HttpStatusCode::OK);
+        let response = handle(&auth, "GET /statsx HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), HttpStatusCode::OK);
+    }
+
+    #[test]
+    fn jwt_claims_should_not_pass_auth() {
+        use crate::middlewares::{JwtClaims, JwtMiddleware};
+        use jsonwebtoken::{EncodingKey, Header, encode};
+
+        let jwt = JwtMiddleware::new().with_hs256_secret(b"secret").protect("/", &[]);
+        let auth = AuthMiddleware::new("admin")
+            .with_bearer(|token| (token == "t0ken").then(|| "service".to_string()))
+            .protect("/stats", &[]);
+        let exp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() + 60;
+        let token = encode(
+            &Header::default(),
+            &serde_json::json!({"sub": "alice", "exp": exp}),
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+
+        // A JWT valid for an outer JwtMiddleware is still no admin credential
+        let raw = format!("GET /stats HTTP/1.1\r\nAuthorization: Bearer {}\r\n\r\n", token);
+        let mut reader = Cursor::new(raw.as_bytes());
+        let mut request = HttpRequest::from_reader(&mut reader).unwrap();
+        let response = jwt.handle(&mut request, &|req: &mut HttpRequest| {
+            assert!(req.get_extension::<JwtClaims>().is_some());
+            auth.handle(req, &|_: &mut HttpRequest| HttpResponse::new(HttpStatusCode::OK))
+        });
+        assert_eq!(response.status_code(), HttpStatusCode::Unauthorized);
+    }
+}
@@ -0,0 +1,270 @@
+use crate::http_error::HttpError;
+use crate::http_request::{HttpMethod, HttpRequest};
+use crate::http_response::{HttpResponse, HttpStatusCode, IntoResponse};
+use crate::middlewares::http_middleware::HttpMiddleware;
+use regex::Regex;
+
+pub enum AllowedOrigin {
+    Any,
+    Exact(String),
+    /// A single `*` standing for any run of characters, e.g. `https://*.example.com`.
+    Wildcard(String),
+    Regex(Regex),
+    Predicate(Box<dyn Fn(&str) -> bool + Send + Sync>),
+}
+
+impl AllowedOrigin {
+    /// `*` allows any origin, a pattern containing `*` becomes a wildcard, anything
+    /// else must match exactly.
+    pub fn parse(s: &str) -> Self {
+        if s == "*" {
+            AllowedOrigin::Any
+        } else if s.contains('*') {
+            AllowedOrigin::Wildcard(s.to_string())
+        } else {
+            AllowedOrigin::Exact(s.to_string())
+        }
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            AllowedOrigin::Any => true,
+            AllowedOrigin::Exact(o) => o.eq_ignore_ascii_case(origin),
+            AllowedOrigin::Wildcard(pattern) => {
+                let (prefix, suffix) = pattern.split_once('*').unwrap_or((pattern, ""));
+                origin.len() >= prefix.len() + suffix.len()
+                    && origin
+                        .get(..prefix.len())
+                        .is_some_and(|p| p.eq_ignore_ascii_case(prefix))
+                    && origin
+                        .get(origin.len() - suffix.len()..)
+                        .is_some_and(|s| s.eq_ignore_ascii_case(suffix))
+            }
+            AllowedOrigin::Regex(re) => re.is_match(origin),
+            AllowedOrigin::Predicate(f) => f(origin),
+        }
+    }
+}
+
+pub struct CorsMiddleware {
+    origins: Vec<AllowedOrigin>,
+    methods: Vec<HttpMethod>,
+    headers: Vec<String>,
+    exposed_headers: Vec<String>,
+    allow_credentials: bool,
+    max_age: Option<u64>,
+}
+
+impl CorsMiddleware {
+    pub fn new() -> Self {
+        CorsMiddleware {
+            origins: Vec::new(),
+            methods: vec![HttpMethod::GET, HttpMethod::HEAD, HttpMethod::POST],
+            headers: Vec::new(),
+            exposed_headers: Vec::new(),
+            allow_credentials: false,
+            max_age: None,
+        }
+    }
+
+    pub fn with_allowed_origin(mut self, origin: AllowedOrigin) -> Self {
+        self.origins.push(origin);
+        self
+    }
+
+    pub fn with_allowed_methods(mut self, methods: Vec<HttpMethod>) -> Self {
+        self.methods = methods;
+        self
+    }
+
+    /// Request headers a preflight may ask for; `*` mirrors whatever is requested.
+    pub fn with_allowed_headers(mut self, headers: &[&str]) -> Self {
+        self.headers = headers.iter().map(|h| h.to_string()).collect();
+        self
+    }
+
+    pub fn with_exposed_headers(mut self, headers: &[&str]) -> Self {
+        self.exposed_headers = headers.iter().map(|h| h.to_string()).collect();
+        self
+    }
+
+    pub fn with_credentials(mut self, allow_credentials: bool) -> Self {
+        self.allow_credentials = allow_credentials;
+        self
+    }
+
+    pub fn with_max_age(mut self, seconds: u64) -> Self {
+        self.max_age = Some(seconds);
+        self
+    }
+
+    fn is_origin_allowed(&self, origin: &str) -> bool {
+        self.origins.iter().any(|o| o.matches(origin))
+    }
+
+    fn allow_origin_value(&self, origin: &str) -> String {
+        // Credentialed requests must name the origin; browsers reject `*` for them
+        let any = self.origins.iter().any(|o| matches!(o, AllowedOrigin::Any));
+        if any && !self.allow_credentials {
+            "*".to_string()
+        } else {
+            origin.to_string()
+        }
+    }
+
+    fn headers_allowed(&self, requested: &str) -> bool {
+        if self.headers.iter().any(|h| h == "*") {
+            return true;
+        }
+        requested
+            .split(',')
+            .map(|h| h.trim())
+            .filter(|h| !h.is_empty())
+            .all(|h| self.headers.iter().any(|a| a.eq_ignore_ascii_case(h)))
+    }
+
+    fn preflight(&self, request: &HttpRequest, origin: &str, method: &str) -> HttpResponse {
+        let requested_headers = request
+            .get_header("Access-Control-Request-Headers")
+            .cloned()
+            .unwrap_or_default();
+        let method_allowed = self.methods.iter().any(|m| m.to_string() == method);
+        if !method_allowed || !self.headers_allowed(&requested_headers) {
+            return HttpError::new(HttpStatusCode::Forbidden, "CORS method or headers not allowed")
+                .into_response()
+                .with_header("Vary", "Origin, Access-Control-Request-Method, Access-Control-Request-Headers");
+        }
+
+        let methods: Vec<String> = self.methods.iter().map(|m| m.to_string()).collect();
+        let mut response = HttpResponse::new(HttpStatusCode::NoContent)
+            .with_header("Access-Control-Allow-Origin", &self.allow_origin_value(origin))
+            .with_header("Access-Control-Allow-Methods", &methods.join(", "))
+            .with_header("Vary", "Origin, Access-Control-Request-Method, Access-Control-Request-Headers");
+        if !requested_headers.is_empty() {
+            let allowed = if self.headers.iter().any(|h| h == "*") {
+                requested_headers
+            } else {
+                self.headers.join(", ")
+            };
+            response.set_header("Access-Control-Allow-Headers", &allowed);
+        }
+        if self.allow_credentials {
+            response.set_header("Access-Control-Allow-Credentials", "true");
+        }
+        if let Some(max_age) = self.max_age {
+            response.set_header("Access-Control-Max-Age", &max_age.to_string());
+        }
+        response
+    }
+}
+
+impl HttpMiddleware for CorsMiddleware {
+    fn handle(
+        &self,
+        request: &mut HttpRequest,
+        next: &dyn Fn(&mut HttpRequest) -> HttpResponse,
+    ) -> HttpResponse {
+        let Some(origin) = request.get_header("Origin").cloned() else {
+            return next(request);
+        };
+        let allowed = self.is_origin_allowed(&origin);
+        if request.method == HttpMethod::OPTIONS
+            && let Some(method) = request.get_header("Access-Control-Request-Method").cloned()
+        {
+            if !allowed {
+                return HttpError::new(HttpStatusCode::Forbidden, "CORS origin not allowed")
+                    .into_response()
+                    .with_header("Vary", "Origin");
+            }
+            return self.preflight(request, &origin, &method);
+        }
+
+        // Same-origin and non-browser requests send `Origin` too, so other origins are
+        // still served; without the headers below the browser keeps the response from
+        // the page.
+        let mut response = next(request);
+        let vary = match response.get_header("Vary") {
+            Some(vary) if !vary.is_empty() => format!("{}, Origin", vary),
+            _ => "Origin".to_string(),
+        };
+        response.set_header("Vary", &vary);
+        if !allowed {
+            return response;
+        }
+        response.set_header("Access-Control-Allow-Origin", &self.allow_origin_value(&origin));
+        if self.allow_credentials {
+            response.set_header("Access-Control-Allow-Credentials", "true");
+        }
+        if !self.exposed_headers.is_empty() {
+            response.set_header("Access-Control-Expose-Headers", &self.exposed_headers.join(", "));
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn handle(cors: &CorsMiddleware, raw: &str) -> HttpResponse {
+        let mut reader = Cursor::new(raw.as_bytes());
+        let mut request = HttpRequest::from_reader(&mut reader).unwrap();
+        cors.handle(&mut request, &|_: &mut HttpRequest| {
+            HttpResponse::new(HttpStatusCode::OK).with_body("handled")
+        })
+    }
+
+    #[test]
+    fn wildcard_origin_should_match_subdomains() {
+        let origin = AllowedOrigin::parse("https://*.example.com");
+        assert!(origin.matches("https://app.example.com"));
+        assert!(!origin.matches("https://example.org"));
+        assert!(!origin.matches("http://app.example.com"));
+    }
+
+    #[test]
+    fn preflight_should_be_answered_without_handler() {
+        let cors = CorsMiddleware::new()
+            .with_allowed_origin(AllowedOrigin::Exact("https://app.example.com".to_string()))
+            .with_allowed_methods(vec![HttpMethod::GET, HttpMethod::PUT])
+            .with_allowed_headers(&["Content-Type"])
+            .with_max_age(600);
+        let response = handle(
+            &cors,
+            "OPTIONS /api HTTP/1.1\r\nOrigin: https://app.example.com\r\nAccess-Control-Request-Method: PUT\r\nAccess-Control-Request-Headers: content-type\r\n\r\n",
+        );
+        assert_eq!(response.status_code(), HttpStatusCode::NoContent);
+        assert_eq!(response.get_header("Access-Control-Allow-Origin").unwrap(), "https://app.example.com");
+        assert_eq!(response.get_header("Access-Control-Allow-Methods").unwrap(), "GET, PUT");
+        assert_eq!(response.get_header("Access-Control-Max-Age").unwrap(), "600");
+        assert!(response.get_body().is_none());
+    }
+
+    #[test]
+    fn disallowed_origin_should_only_be_rejected_in_preflight() {
+        let cors = CorsMiddleware::new().with_allowed_origin(AllowedOrigin::parse("https://a.com"));
+        let response = handle(&cors, "POST /api HTTP/1.1\r\nOrigin: https://evil.com\r\n\r\n");
+        assert_eq!(response.status_code(), HttpStatusCode::OK);
+        assert!(response.get_header("Access-Control-Allow-Origin").is_none());
+        assert_eq!(response.get_header("Vary").unwrap(), "Origin");
+
+        let response = handle(
+            &cors,
+            "OPTIONS /api HTTP/1.1\r\nOrigin: https://evil.com\r\nAccess-Control-Request-Method: PUT\r\n\r\n",
+        );
+        assert_eq!(response.status_code(), HttpStatusCode::Forbidden);
+    }
+
+    #[test]
+    fn simple_request_should_get_cors_headers() {
+        let cors = CorsMiddleware::new()
+            .with_allowed_origin(AllowedOrigin::Any)
+            .with_exposed_headers(&["X-Total"]);
+        let response = handle(&cors, "GET /api HTTP/1.1\r\nOrigin: https://a.com\r\n\r\n");
+        assert_eq!(response.status_code(), HttpStatusCode::OK);
+        assert_eq!(response.get_header("Access-Control-Allow-Origin").unwrap(), "*");
+        assert_eq!(response.get_header("Vary").unwrap(), "Origin");
+        assert_eq!(response.get_header("Access-Control-Expose-Headers").unwrap(), "X-Total");
+    }
+}
@@ -1,5 +1,6 @@
 use crate::http_request::HttpRequest;
 use crate::http_response::HttpResponse;
+use crate::metrics;
 use crate::middlewares::http_middleware::HttpMiddleware;
 use flate2::write::GzEncoder;
 use flate2::Compression;
@@ -37,6 +38,10 @@ impl HttpMiddleware for EncodingMiddleware {
                 encoder.write_all(body).unwrap();
             }
             let compressed_body = encoder.finish().unwrap();
+            metrics::global().record_compression(
+                response.get_body().map(|b| b.len()).unwrap_or(0),
+                compressed_body.len(),
+            );
 
             let content_type = response
                 .get_header("Content-Type")
@@ -0,0 +1,207 @@
+use crate::client_ip::IpCidr;
+use crate::http_error::HttpError;
+use crate::http_request::HttpRequest;
+use crate::http_response::{HttpResponse, HttpStatusCode, IntoResponse};
+use crate::logger::log;
+use crate::middlewares::http_middleware::HttpMiddleware;
+use std::net::IpAddr;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IpFilterRule {
+    prefix: String,
+    allow: Vec<IpCidr>,
+    deny: Vec<IpCidr>,
+}
+
+impl IpFilterRule {
+    pub fn new(prefix: &str) -> Self {
+        IpFilterRule {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn allow(mut self, cidr: IpCidr) -> Self {
+        self.allow.push(cidr);
+        self
+    }
+
+    pub fn deny(mut self, cidr: IpCidr) -> Self {
+        self.deny.push(cidr);
+        self
+    }
+
+    fn matches_path(&self, path: &str) -> bool {
+        match path.strip_prefix(&self.prefix) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+
+    /// Deny entries win; a non-empty allow list admits only its members.
+    fn permits(&self, ip: &IpAddr) -> bool {
+        if self.deny.iter().any(|c| c.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|c| c.contains(ip))
+    }
+}
+
+/// Parses rule files made of `<path prefix> <allow|deny> <cidr>` lines; `#` starts a comment.
+pub fn parse_rules(contents: &str) -> Result<Vec<IpFilterRule>, String> {
+    let mut rules: Vec<IpFilterRule> = Vec::new();
+    for (n, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let [prefix, action, cidr] = parts[..] else {
+            return Err(format!("line {}: expected `<prefix> <allow|deny> <cidr>`", n + 1));
+        };
+        let cidr: IpCidr = cidr.parse().map_err(|e| format!("line {}: {}", n + 1, e))?;
+        let prefix = prefix.trim_end_matches('/');
+        let index = match rules.iter().position(|r| r.prefix == prefix) {
+            Some(index) => index,
+            None => {
+                rules.push(IpFilterRule::new(prefix));
+                rules.len() - 1
+            }
+        };
+        match action {
+            "allow" => rules[index].allow.push(cidr),
+            "deny" => rules[index].deny.push(cidr),
+            _ => return Err(format!("line {}: unknown action {}", n + 1, action)),
+        }
+    }
+    Ok(rules)
+}
+
+struct RulesFile {
+    path: String,
+    modified: Option<SystemTime>,
+    checked: Instant,
+}
+
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+pub struct IpFilterMiddleware {
+    rules: RwLock<Vec<IpFilterRule>>,
+    file: Option<Mutex<RulesFile>>,
+}
+
+impl IpFilterMiddleware {
+    pub fn new(rules: Vec<IpFilterRule>) -> Self {
+        IpFilterMiddleware {
+            rules: RwLock::new(rules),
+            file: None,
+        }
+    }
+
+    /// Loads rules from `path` and picks up changes to the file while running.
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        Ok(IpFilterMiddleware {
+            rules: RwLock::new(parse_rules(&contents)?),
+            file: Some(Mutex::new(RulesFile {
+                path: path.to_string(),
+                modified,
+                checked: Instant::now(),
+            })),
+        })
+    }
+
+    pub fn set_rules(&self, rules: Vec<IpFilterRule>) {
+        *self.rules.write().unwrap() = rules;
+    }
+
+    /// Re-reads the rules file if it changed; a broken file leaves the current rules in place.
+    fn reload_if_changed(&self) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        let Ok(mut file) = file.try_lock() else {
+            return;
+        };
+        if file.checked.elapsed() < RELOAD_CHECK_INTERVAL {
+            return;
+        }
+        file.checked = Instant::now();
+        let modified = std::fs::metadata(&file.path).and_then(|m| m.modified()).ok();
+        if modified == file.modified {
+            return;
+        }
+        let parsed = std::fs::read_to_string(&file.path)
+            .map_err(|e| e.to_string())
+            .and_then(|c| parse_rules(&c));
+        match parsed {
+            Ok(rules) => {
+                file.modified = modified;
+                self.set_rules(rules);
+            }
+            Err(e) => log!(
+                Warn,
+                "Keeping previous IP rules, failed to reload",
+                file = file.path,
+                error = e
+            ),
+        }
+    }
+}
+
+impl HttpMiddleware for IpFilterMiddleware {
+    fn handle(
+        &self,
+        request: &mut HttpRequest,
+        next: &dyn Fn(&mut HttpRequest) -> HttpResponse,
+    ) -> HttpResponse {
+        self.reload_if_changed();
+        let permitted = {
+            let rules = self.rules.read().unwrap();
+            let rule = rules
+                .iter()
+                .filter(|r| r.matches_path(&request.path))
+                .max_by_key(|r| r.prefix.len());
+            match (rule, request.client_ip) {
+                (None, _) => true,
+                (Some(rule), Some(ip)) => rule.permits(&ip),
+                (Some(_), None) => false,
+            }
+        };
+        if permitted {
+            next(request)
+        } else {
+            HttpError::new(HttpStatusCode::Forbidden, "Client address not allowed").into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rules_should_group_by_prefix() {
+        let rules = parse_rules("# admin\n/stats allow 10.0.0.0/8\n/stats/ deny 10.0.0.13 # laptop\n/files allow ::1\n").unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].allow.len(), 1);
+        assert_eq!(rules[0].deny.len(), 1);
+        assert!(parse_rules("/stats permit 10.0.0.0/8").is_err());
+        assert!(parse_rules("/stats allow").is_err());
+    }
+
+    #[test]
+    fn deny_should_win_over_allow() {
+        let rule = IpFilterRule::new("/stats")
+            .allow("10.0.0.0/8".parse().unwrap())
+            .deny("10.0.0.13".parse().unwrap());
+        assert!(rule.permits(&"10.1.2.3".parse().unwrap()));
+        assert!(!rule.permits(&"10.0.0.13".parse().unwrap()));
+        assert!(!rule.permits(&"192.168.0.1".parse().unwrap()));
+        assert!(rule.matches_path("/stats"));
+        assert!(!rule.matches_path("/statsx"));
+    }
+}
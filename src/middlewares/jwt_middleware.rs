@@ -0,0 +1,312 @@
+use crate::http_error::HttpError;
+use crate::http_request::HttpRequest;
+use crate::http_response::{HttpResponse, HttpStatusCode, IntoResponse};
+use crate::middlewares::http_middleware::HttpMiddleware;
+use jsonwebtoken::jwk::{AlgorithmParameters, JwkSet};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde_json::{json, Value};
+use std::str::FromStr;
+
+/// Decoded payload of a validated token, available to handlers via
+/// `request.get_extension::<JwtClaims>()`.
+#[derive(Debug, Clone)]
+pub struct JwtClaims(pub Value);
+
+impl JwtClaims {
+    /// Scopes from a space-separated `scope` claim or a `scp` array.
+    pub fn scopes(&self) -> Vec<String> {
+        if let Some(scope) = self.0.get("scope").and_then(|s| s.as_str()) {
+            return scope.split_whitespace().map(|s| s.to_string()).collect();
+        }
+        match self.0.get("scp") {
+            Some(Value::Array(items)) => items
+                .iter()
+                .filter_map(|s| s.as_str())
+                .map(|s| s.to_string())
+                .collect(),
+            Some(Value::String(s)) => s.split_whitespace().map(|s| s.to_string()).collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+struct JwtKey {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+struct ScopedRoute {
+    prefix: String,
+    scopes: Vec<String>,
+}
+
+pub struct JwtMiddleware {
+    keys: Vec<JwtKey>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    leeway: u64,
+    routes: Vec<ScopedRoute>,
+}
+
+impl JwtMiddleware {
+    pub fn new() -> Self {
+        JwtMiddleware {
+            keys: Vec::new(),
+            issuer: None,
+            audience: None,
+            leeway: 60,
+            routes: Vec::new(),
+        }
+    }
+
+    pub fn with_hs256_secret(mut self, secret: &[u8]) -> Self {
+        self.keys.push(JwtKey {
+            kid: None,
+            algorithm: Algorithm::HS256,
+            key: DecodingKey::from_secret(secret),
+        });
+        self
+    }
+
+    pub fn with_rs256_pem(mut self, pem: &[u8]) -> jsonwebtoken::errors::Result<Self> {
+        self.keys.push(JwtKey {
+            kid: None,
+            algorithm: Algorithm::RS256,
+            key: DecodingKey::from_rsa_pem(pem)?,
+        });
+        Ok(self)
+    }
+
+    pub fn with_es256_pem(mut self, pem: &[u8]) -> jsonwebtoken::errors::Result<Self> {
+        self.keys.push(JwtKey {
+            kid: None,
+            algorithm: Algorithm::ES256,
+            key: DecodingKey::from_ec_pem(pem)?,
+        });
+        Ok(self)
+    }
+
+    /// Adds every HS256, RS256 and ES256 key of a JWKS document. Keys without `alg`
+    /// get the algorithm implied by their key type.
+    pub fn with_jwks(mut self, jwks: &str) -> std::io::Result<Self> {
+        let set: JwkSet = serde_json::from_str(jwks)?;
+        for jwk in &set.keys {
+            let algorithm = match (&jwk.common.key_algorithm, &jwk.algorithm) {
+                (Some(alg), _) => Algorithm::from_str(&alg.to_string()).ok(),
+                (None, AlgorithmParameters::OctetKey(_)) => Some(Algorithm::HS256),
+                (None, AlgorithmParameters::RSA(_)) => Some(Algorithm::RS256),
+                (None, AlgorithmParameters::EllipticCurve(_)) => Some(Algorithm::ES256),
+                _ => None,
+            };
+            let Some(algorithm @ (Algorithm::HS256 | Algorithm::RS256 | Algorithm::ES256)) = algorithm
+            else {
+                continue;
+            };
+            let key = DecodingKey::from_jwk(jwk)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            self.keys.push(JwtKey {
+                kid: jwk.common.key_id.clone(),
+                algorithm,
+                key,
+            });
+        }
+        Ok(self)
+    }
+
+    pub fn with_jwks_file(self, path: &str) -> std::io::Result<Self> {
+        let jwks = std::fs::read_to_string(path)?;
+        self.with_jwks(&jwks)
+    }
+
+    pub fn with_issuer(mut self, issuer: &str) -> Self {
+        self.issuer = Some(issuer.to_string());
+        self
+    }
+
+    pub fn with_audience(mut self, audience: &str) -> Self {
+        self.audience = Some(audience.to_string());
+        self
+    }
+
+    /// Clock skew tolerated when checking `exp` and `nbf`, in seconds.
+    pub fn with_leeway(mut self, seconds: u64) -> Self {
+        self.leeway = seconds;
+        self
+    }
+
+    /// Requires a valid token carrying all of `scopes` for paths under `prefix`.
+    pub fn protect(mut self, prefix: &str, scopes: &[&str]) -> Self {
+        self.routes.push(ScopedRoute {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            scopes: scopes.iter().map(|s| s.to_string()).collect(),
+        });
+        self
+    }
+
+    fn validate(&self, token: &str) -> Result<JwtClaims, String> {
+        let header = jsonwebtoken::decode_header(token).map_err(|e| e.to_string())?;
+        let candidates = self.keys.iter().filter(|k| {
+            k.algorithm == header.alg
+                && match (&header.kid, &k.kid) {
+                    (Some(kid), Some(key_kid)) => kid == key_kid,
+                    _ => true,
+                }
+        });
+
+        let mut last_error = "No key for token algorithm".to_string();
+        for candidate in candidates {
+            let mut validation = Validation::new(candidate.algorithm);
+            validation.leeway = self.leeway;
+            validation.validate_nbf = true;
+            match &self.issuer {
+                Some(issuer) => validation.set_issuer(&[issuer]),
+                None => validation.iss = None,
+            }
+            match &self.audience {
+                Some(audience) => validation.set_audience(&[audience]),
+                None => validation.validate_aud = false,
+            }
+            match jsonwebtoken::decode::<Value>(token, &candidate.key, &validation) {
+                Ok(data) => return Ok(JwtClaims(data.claims)),
+                Err(e) => last_error = e.to_string(),
+            }
+        }
+        Err(last_error)
+    }
+}
+
+fn unauthorized(description: Option<&str>) -> HttpResponse {
+    let challenge = match description {
+        Some(description) => format!(
+            "Bearer error=\"invalid_token\", error_description=\"{}\"",
+            description.replace('"', "'")
+        ),
+        None => "Bearer".to_string(),
+    };
+    HttpError::new(HttpStatusCode::Unauthorized, "Missing or invalid bearer token")
+        .into_response()
+        .with_header("WWW-Authenticate", &challenge)
+}
+
+impl HttpMiddleware for JwtMiddleware {
+    fn handle(
+        &self,
+        request: &mut HttpRequest,
+        next: &dyn Fn(&mut HttpRequest) -> HttpResponse,
+    ) -> HttpResponse {
+        let path = request.path.clone();
+        let routes: Vec<&ScopedRoute> = self
+            .routes
+            .iter()
+            .filter(|r| match path.strip_prefix(&r.prefix) {
+                Some(rest) => rest.is_empty() || rest.starts_with('/'),
+                None => false,
+            })
+            .collect();
+        if routes.is_empty() {
+            return next(request);
+        }
+
+        let token = request.get_header("Authorization").and_then(|v| {
+            let (scheme, token) = v.split_once(' ')?;
+            scheme.eq_ignore_ascii_case("Bearer").then(|| token.trim().to_string())
+        });
+        let Some(token) = token else {
+            return unauthorized(None);
+        };
+        let claims = match self.validate(&token) {
+            Ok(claims) => claims,
+            Err(e) => return unauthorized(Some(&e)),
+        };
+
+        let granted = claims.scopes();
+        let required: Vec<&String> = routes.iter().flat_map(|r| &r.scopes).collect();
+        if required.iter().any(|s| !granted.contains(s)) {
+            let scope: Vec<&str> = required.iter().map(|s| s.as_str()).collect();
+            return HttpError::new(HttpStatusCode::Forbidden, "Token lacks a required scope")
+                .with_details(json!({ "required_scopes": scope }))
+                .into_response()
+                .with_header(
+                    "WWW-Authenticate",
+                    &format!("Bearer error=\"insufficient_scope\", scope=\"{}\"", scope.join(" ")),
+                );
+        }
+
+        request.insert_extension(claims);
+        next(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header, encode};
+    use serde_json::json;
+    use std::io::Cursor;
+
+    fn now() -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn token(claims: Value) -> String {
+        encode(&Header::default(), &claims, &EncodingKey::from_secret(b"secret")).unwrap()
+    }
+
+    fn handle(jwt: &JwtMiddleware, token: &str) -> HttpResponse {
+        let raw = format!("GET /api/items HTTP/1.1\r\nAuthorization: Bearer {}\r\n\r\n", token);
+        let mut reader = Cursor::new(raw.as_bytes());
+        let mut request = HttpRequest::from_reader(&mut reader).unwrap();
+        jwt.handle(&mut request, &|req: &mut HttpRequest| {
+            let sub = req.get_extension::<JwtClaims>().unwrap().0["sub"].clone();
+            HttpResponse::new(HttpStatusCode::OK).with_body(sub.as_str().unwrap())
+        })
+    }
+
+    fn middleware() -> JwtMiddleware {
+        JwtMiddleware::new()
+            .with_hs256_secret(b"secret")
+            .with_issuer("idp")
+            .with_audience("api")
+            .with_leeway(5)
+            .protect("/api", &["items:read"])
+    }
+
+    #[test]
+    fn valid_token_should_expose_claims() {
+        let t = token(json!({"sub": "alice", "iss": "idp", "aud": "api", "exp": now() + 60, "scope": "items:read other"}));
+        let response = handle(&middleware(), &t);
+        assert_eq!(response.status_code(), HttpStatusCode::OK);
+        assert_eq!(response.get_body().unwrap(), b"alice");
+    }
+
+    #[test]
+    fn invalid_tokens_should_be_rejected() {
+        let expired = token(json!({"sub": "a", "iss": "idp", "aud": "api", "exp": now() - 60, "scope": "items:read"}));
+        assert_eq!(handle(&middleware(), &expired).status_code(), HttpStatusCode::Unauthorized);
+
+        let wrong_aud = token(json!({"sub": "a", "iss": "idp", "aud": "web", "exp": now() + 60, "scope": "items:read"}));
+        assert_eq!(handle(&middleware(), &wrong_aud).status_code(), HttpStatusCode::Unauthorized);
+
+        let not_yet = token(json!({"sub": "a", "iss": "idp", "aud": "api", "exp": now() + 60, "nbf": now() + 60, "scope": "items:read"}));
+        assert_eq!(handle(&middleware(), &not_yet).status_code(), HttpStatusCode::Unauthorized);
+
+        let no_scope = token(json!({"sub": "a", "iss": "idp", "aud": "api", "exp": now() + 60, "scp": ["other"]}));
+        assert_eq!(handle(&middleware(), &no_scope).status_code(), HttpStatusCode::Forbidden);
+    }
+
+    #[test]
+    fn jwks_keys_should_be_matched_by_kid() {
+        let jwks = r#"{"keys": [{"kty": "oct", "kid": "k1", "k": "c2VjcmV0"}]}"#;
+        let jwt = JwtMiddleware::new().with_jwks(jwks).unwrap().protect("/api", &[]);
+        let header = Header {
+            kid: Some("k1".to_string()),
+            ..Default::default()
+        };
+        let t = encode(&header, &json!({"sub": "bob", "exp": now() + 60}), &EncodingKey::from_secret(b"secret")).unwrap();
+        assert_eq!(handle(&jwt, &t).get_body().unwrap(), b"bob");
+    }
+}
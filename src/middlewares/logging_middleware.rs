@@ -1,20 +1,278 @@
-use crate::http_request::HttpRequest;
+use crate::http_request::{HttpMethod, HttpRequest};
 use crate::http_response::HttpResponse;
+use crate::log_writer::{LogSink, LogWriter};
+use crate::middlewares::Principal;
 use crate::middlewares::http_middleware::HttpMiddleware;
+use crate::middlewares::RequestId;
+use crate::timestamp;
+use std::time::{Instant, SystemTime};
 
-pub struct LoggingMiddleware;
+/// Access log line layout. The Apache-style directives understood in templates are
+/// `%h %l %u %t %r %s %>s %b %B %D %T %L %m %U %q %H %%`, `%{Name}i` for request and
+/// `%{Name}o` for response headers.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AccessLogFormat {
+    /// Common Log Format followed by the duration in microseconds and request id.
+    Common,
+    /// Combined Log Format followed by the duration in microseconds and request id.
+    Combined,
+    Template(String),
+}
+
+impl AccessLogFormat {
+    fn template(&self) -> &str {
+        match self {
+            AccessLogFormat::Common => "%h %l %u %t \"%r\" %>s %b %D %L",
+            AccessLogFormat::Combined => {
+                "%h %l %u %t \"%r\" %>s %b \"%{Referer}i\" \"%{User-Agent}i\" %D %L"
+            }
+            AccessLogFormat::Template(t) => t,
+        }
+    }
+}
+
+impl std::str::FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "common" => Ok(AccessLogFormat::Common),
+            "combined" => Ok(AccessLogFormat::Combined),
+            template => Ok(AccessLogFormat::Template(template.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Literal(String),
+    RemoteHost,
+    RemoteLogname,
+    RemoteUser,
+    Time,
+    RequestLine,
+    Status,
+    BytesClf,
+    Bytes,
+    Micros,
+    Seconds,
+    RequestId,
+    Method,
+    Path,
+    Query,
+    Protocol,
+    RequestHeader(String),
+    ResponseHeader(String),
+}
+
+fn parse_template(template: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut literal = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            literal.push(c);
+            continue;
+        }
+        let mut name = None;
+        if chars.peek() == Some(&'{') {
+            chars.next();
+            name = Some(chars.by_ref().take_while(|c| *c != '}').collect::<String>());
+        }
+        if chars.peek() == Some(&'>') {
+            chars.next();
+        }
+        let token = match (chars.next(), name) {
+            (Some('%'), _) => {
+                literal.push('%');
+                continue;
+            }
+            (Some('h'), _) => Token::RemoteHost,
+            (Some('l'), _) => Token::RemoteLogname,
+            (Some('u'), _) => Token::RemoteUser,
+            (Some('t'), _) => Token::Time,
+            (Some('r'), _) => Token::RequestLine,
+            (Some('s'), _) => Token::Status,
+            (Some('b'), _) => Token::BytesClf,
+            (Some('B'), _) => Token::Bytes,
+            (Some('D'), _) => Token::Micros,
+            (Some('T'), _) => Token::Seconds,
+            (Some('L'), _) => Token::RequestId,
+            (Some('m'), _) => Token::Method,
+            (Some('U'), _) => Token::Path,
+            (Some('q'), _) => Token::Query,
+            (Some('H'), _) => Token::Protocol,
+            (Some('i'), Some(name)) => Token::RequestHeader(name),
+            (Some('o'), Some(name)) => Token::ResponseHeader(name),
+            (other, _) => {
+                // Unknown directives are kept verbatim
+                literal.push('%');
+                literal.extend(other);
+                continue;
+            }
+        };
+        if !literal.is_empty() {
+            tokens.push(Token::Literal(std::mem::take(&mut literal)));
+        }
+        tokens.push(token);
+    }
+    if !literal.is_empty() {
+        tokens.push(Token::Literal(literal));
+    }
+    tokens
+}
+
+/// Escapes client-controlled text the way Apache's mod_log_config does, so a value
+/// can't forge a line or break out of a quoted field.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub struct LoggingMiddleware {
+    tokens: Vec<Token>,
+    writer: LogWriter,
+}
 
 impl LoggingMiddleware {
-    pub fn new() -> Self {
-        LoggingMiddleware
+    pub fn with_format(format: AccessLogFormat, sink: LogSink) -> std::io::Result<Self> {
+        Ok(LoggingMiddleware {
+            tokens: parse_template(format.template()),
+            writer: LogWriter::new(sink, "access-log")?,
+        })
+    }
+
+    fn format_line(
+        &self,
+        request: &HttpRequest,
+        response: &HttpResponse,
+        started: SystemTime,
+        micros: u128,
+    ) -> String {
+        let bytes = if request.method == HttpMethod::HEAD || !response.status_code().allows_body() {
+            0
+        } else {
+            response.get_body().map(|b| b.len()).unwrap_or(0)
+        };
+        let or_dash = |v: Option<&String>| v.map(|v| escape(v)).unwrap_or_else(|| "-".to_string());
+        let mut line = String::new();
+        for token in &self.tokens {
+            match token {
+                Token::Literal(s) => line.push_str(s),
+                Token::RemoteHost => line.push_str(
+                    &request
+                        .client_ip
+                        .map(|ip| ip.to_string())
+                        .unwrap_or_else(|| "-".to_string()),
+                ),
+                Token::RemoteLogname => line.push('-'),
+                Token::RemoteUser => line.push_str(&or_dash(
+                    request.get_extension::<Principal>().map(|p| &p.name),
+                )),
+                Token::Time => line.push_str(&format!("[{}]", timestamp::clf(started))),
+                Token::RequestLine => {
+                    line.push_str(&format!("{} {}", request.method, escape(&request.path)));
+                    if !request.query.is_empty() {
+                        line.push('?');
+                        line.push_str(&escape(&request.query));
+                    }
+                    line.push(' ');
+                    line.push_str(&escape(&request.http_version));
+                }
+                Token::Status => line.push_str(&response.status_code().as_u16().to_string()),
+                Token::BytesClf if bytes == 0 => line.push('-'),
+                Token::BytesClf | Token::Bytes => line.push_str(&bytes.to_string()),
+                Token::Micros => line.push_str(&micros.to_string()),
+                Token::Seconds => line.push_str(&(micros / 1_000_000).to_string()),
+                Token::RequestId => line.push_str(&or_dash(
+                    request.get_extension::<RequestId>().map(|r| &r.0),
+                )),
+                Token::Method => line.push_str(&request.method.to_string()),
+                Token::Path => line.push_str(&escape(&request.path)),
+                Token::Query if request.query.is_empty() => {}
+                Token::Query => line.push_str(&format!("?{}", escape(&request.query))),
+                Token::Protocol => line.push_str(&escape(&request.http_version)),
+                Token::RequestHeader(name) => line.push_str(&or_dash(request.get_header(name))),
+                Token::ResponseHeader(name) => line.push_str(&or_dash(
+                    response
+                        .get_headers()
+                        .iter()
+                        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                        .map(|(_, v)| v),
+                )),
+            }
+        }
+        line
     }
 }
 
 impl HttpMiddleware for LoggingMiddleware {
     fn handle(&self, request: &mut HttpRequest, next: &dyn Fn(&mut HttpRequest) -> HttpResponse) -> HttpResponse {
-        println!("Received request: {} {}", request.method, request.path);
+        let started = SystemTime::now();
+        let timer = Instant::now();
         let response = next(request);
-        println!("Responding with status: {}", response.status_code());
+        let micros = timer.elapsed().as_micros();
+        self.writer
+            .write_line(self.format_line(request, &response, started, micros));
         response
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_response::HttpStatusCode;
+    use std::io::Cursor;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn parse_template_should_understand_directives() {
+        let tokens = parse_template("%h \"%{User-Agent}i\" %>s %% %z");
+        assert_eq!(
+            tokens,
+            vec![
+                Token::RemoteHost,
+                Token::Literal(" \"".to_string()),
+                Token::RequestHeader("User-Agent".to_string()),
+                Token::Literal("\" ".to_string()),
+                Token::Status,
+                Token::Literal(" % %z".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn combined_line_should_match_apache() {
+        let logging = LoggingMiddleware::with_format(AccessLogFormat::Combined, LogSink::Stdout).unwrap();
+        let raw = "GET /echo/abc?x=1 HTTP/1.1\r\nUser-Agent: curl/8.5.0\r\n\r\n";
+        let mut reader = Cursor::new(raw.as_bytes());
+        let mut request = HttpRequest::from_reader(&mut reader).unwrap();
+        request.client_ip = Some("127.0.0.1".parse().unwrap());
+        request.insert_extension(RequestId("abc-123".to_string()));
+        let response = HttpResponse::new(HttpStatusCode::OK).with_body("abc");
+        let started = UNIX_EPOCH + Duration::from_secs(971_186_136);
+        assert_eq!(
+            logging.format_line(&request, &response, started, 1234),
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /echo/abc?x=1 HTTP/1.1\" 200 3 \"-\" \"curl/8.5.0\" 1234 abc-123"
+        );
+    }
+
+    #[test]
+    fn client_values_should_be_escaped() {
+        let logging = LoggingMiddleware::with_format(AccessLogFormat::Combined, LogSink::Stdout).unwrap();
+        let raw = "GET /a\"b HTTP/1.1\r\nReferer: x\\y\r\nUser-Agent: evil\" \x1b[31m\r\n\r\n";
+        let mut reader = Cursor::new(raw.as_bytes());
+        let request = HttpRequest::from_reader(&mut reader).unwrap();
+        let response = HttpResponse::new(HttpStatusCode::OK);
+        let line = logging.format_line(&request, &response, UNIX_EPOCH, 0);
+        assert!(line.contains("\"GET /a\\\"b HTTP/1.1\" 200 - \"x\\\\y\" \"evil\\\" \\x1b[31m\""), "{}", line);
+    }
+}
@@ -0,0 +1,74 @@
+use crate::http_request::{HttpMethod, HttpRequest};
+use crate::http_response::{HttpResponse, HttpStatusCode};
+use crate::metrics;
+use crate::middlewares::http_middleware::HttpMiddleware;
+use crate::middlewares::MatchedRoute;
+use std::time::Instant;
+
+/// Records request durations into the global registry.
+pub struct MetricsMiddleware;
+
+/// Serves the global registry at `path`, registered apart from the recording
+/// [`MetricsMiddleware`] so it can sit behind access control.
+pub struct MetricsEndpointMiddleware {
+    path: String,
+}
+
+impl MetricsMiddleware {
+    pub fn new() -> Self {
+        MetricsMiddleware
+    }
+
+    pub fn endpoint(path: &str) -> MetricsEndpointMiddleware {
+        MetricsEndpointMiddleware {
+            path: path.to_string(),
+        }
+    }
+}
+
+impl HttpMiddleware for MetricsEndpointMiddleware {
+    fn handle(
+        &self,
+        request: &mut HttpRequest,
+        next: &dyn Fn(&mut HttpRequest) -> HttpResponse,
+    ) -> HttpResponse {
+        if request.path == self.path
+            && (request.method == HttpMethod::GET || request.method == HttpMethod::HEAD)
+        {
+            request.insert_extension(MatchedRoute(self.path.clone()));
+            return HttpResponse::new(HttpStatusCode::OK)
+                .with_body(&metrics::global().render())
+                .with_header("Content-Type", "text/plain; version=0.0.4; charset=utf-8");
+        }
+        next(request)
+    }
+}
+
+impl HttpMiddleware for MetricsMiddleware {
+    fn handle(
+        &self,
+        request: &mut HttpRequest,
+        next: &dyn Fn(&mut HttpRequest) -> HttpResponse,
+    ) -> HttpResponse {
+        let registry = metrics::global();
+        let _in_flight = registry.request_started();
+        let timer = Instant::now();
+        let response = next(request);
+        // Unrouted paths and extension methods share series to bound cardinality
+        let route = request
+            .get_extension::<MatchedRoute>()
+            .map(|r| r.0.as_str())
+            .unwrap_or("unmatched");
+        let method = match &request.method {
+            HttpMethod::Extension(_) => "OTHER".to_string(),
+            method => method.to_string(),
+        };
+        registry.observe_request(
+            route,
+            &method,
+            response.status_code(),
+            timer.elapsed(),
+        );
+        response
+    }
+}
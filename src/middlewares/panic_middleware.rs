@@ -1,14 +1,42 @@
-use std::panic::{self,AssertUnwindSafe};
+use crate::http_error::HttpError;
 use crate::http_request::HttpRequest;
-use crate::http_response::{HttpResponse, HttpStatusCode};
+use crate::http_response::{HttpResponse, HttpStatusCode, IntoResponse};
 use crate::middlewares::http_middleware::HttpMiddleware;
+use crate::middlewares::RequestId;
+use crate::panics::{self, CaughtPanic};
+use serde_json::json;
 
-
-pub struct PanicMiddleware;
+/// Turns panics in inner middlewares and handlers into 500 responses carrying an
+/// error id that matches the logged panic. The panic itself is only shown in dev mode.
+pub struct PanicMiddleware {
+    dev_mode: bool,
+}
 
 impl PanicMiddleware {
     pub fn new() -> Self {
-        PanicMiddleware
+        PanicMiddleware { dev_mode: false }
+    }
+
+    /// Includes the panic message, location and backtrace in responses.
+    pub fn with_dev_mode(mut self, dev_mode: bool) -> Self {
+        self.dev_mode = dev_mode;
+        self
+    }
+
+    /// A 500 carrying the ids needed to find the logged panic, rendered like every
+    /// other error.
+    fn error(&self, panic: &CaughtPanic, request_id: Option<&str>) -> HttpError {
+        let mut details = json!({ "error_id": panic.error_id });
+        if let Some(id) = request_id {
+            details["request_id"] = json!(id);
+        }
+        if self.dev_mode {
+            details["panic"] = json!(panic.message);
+            details["location"] = json!(panic.location);
+            details["backtrace"] = json!(panic.backtrace);
+        }
+        HttpError::new(HttpStatusCode::InternalServerError, "Unexpected error while handling the request")
+            .with_details(details)
     }
 }
 
@@ -18,11 +46,53 @@ impl HttpMiddleware for PanicMiddleware {
         req: &mut HttpRequest,
         next: &dyn Fn(&mut HttpRequest) -> HttpResponse,
     ) -> HttpResponse {
-        let result = panic::catch_unwind(AssertUnwindSafe(|| next(req)));
-        match result {
+        match panics::catch(|| next(req)) {
             Ok(response) => response,
-            Err(_) => HttpResponse::new(HttpStatusCode::InternalServerError)
-                .with_body("Internal Server Error"),
+            Err(panic) => {
+                panic.log("Request handler panicked");
+                let response = self
+                    .error(&panic, req.get_extension::<RequestId>().map(|r| r.0.as_str()))
+                    .into_response();
+                // Lets outer middlewares such as statistics tell panics from other 500s
+                req.insert_extension(panic);
+                response
+            }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn handle(panic_middleware: &PanicMiddleware, raw: &str) -> HttpResponse {
+        let mut reader = Cursor::new(raw.as_bytes());
+        let mut request = HttpRequest::from_reader(&mut reader).unwrap();
+        request.insert_extension(RequestId("req-1".to_string()));
+        let response = panic_middleware.handle(&mut request, &|_| panic!("<oops>"));
+        assert!(request.get_extension::<CaughtPanic>().is_some());
+        response
+    }
+
+    #[test]
+    fn response_should_use_the_error_renderer() {
+        let response = handle(&PanicMiddleware::new(), "GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), HttpStatusCode::InternalServerError);
+        assert_eq!(response.get_header("Content-Type").unwrap(), "application/problem+json");
+        let body: serde_json::Value = serde_json::from_slice(response.get_body().unwrap()).unwrap();
+        assert_eq!(body["status"], 500);
+        assert_eq!(body["request_id"], "req-1");
+        assert_eq!(body["error_id"].as_str().unwrap().len(), 32);
+        assert!(body.get("panic").is_none());
+        assert!(!String::from_utf8_lossy(response.get_body().unwrap()).contains("oops"));
+    }
+
+    #[test]
+    fn dev_mode_should_include_details() {
+        let response = handle(&PanicMiddleware::new().with_dev_mode(true), "GET / HTTP/1.1\r\n\r\n");
+        let body: serde_json::Value = serde_json::from_slice(response.get_body().unwrap()).unwrap();
+        assert_eq!(body["panic"], "<oops>");
+        assert!(body["location"].as_str().unwrap().contains("panic_middleware.rs"));
+    }
+}
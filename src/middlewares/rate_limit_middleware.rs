@@ -0,0 +1,255 @@
+use crate::http_error::HttpError;
+use crate::http_request::HttpRequest;
+use crate::http_response::{HttpResponse, HttpStatusCode, IntoResponse};
+use crate::middlewares::http_middleware::HttpMiddleware;
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+type KeyExtractor = Box<dyn Fn(&HttpRequest) -> Option<String> + Send + Sync>;
+
+/// What identifies a client for rate limiting purposes.
+pub enum RateLimitKey {
+    ClientIp,
+    /// Value of a request header, such as an API key.
+    Header(String),
+    Custom(KeyExtractor),
+}
+
+/// `ip`, `header:<name>` or `query:<name>`, the last keying on a query parameter.
+impl std::str::FromStr for RateLimitKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "ip" => Ok(RateLimitKey::ClientIp),
+            Some(("header", name)) if !name.is_empty() => Ok(RateLimitKey::Header(name.to_string())),
+            Some(("query", name)) if !name.is_empty() => {
+                let name = name.to_string();
+                Ok(RateLimitKey::Custom(Box::new(move |request| {
+                    request.query_params.get(&name).cloned()
+                })))
+            }
+            _ => Err(format!("Invalid rate limit key: {}", s)),
+        }
+    }
+}
+
+/// `requests` per `period`, allowing bursts of up to `requests`. A limit of zero
+/// would never refill, so it can't be expressed.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub requests: NonZeroU32,
+    pub period: Duration,
+}
+
+impl RateLimit {
+    pub fn per_second(requests: NonZeroU32) -> Self {
+        RateLimit { requests, period: Duration::from_secs(1) }
+    }
+
+    pub fn per_minute(requests: NonZeroU32) -> Self {
+        RateLimit { requests, period: Duration::from_secs(60) }
+    }
+
+    fn refill_per_sec(&self) -> f64 {
+        self.requests.get() as f64 / self.period.as_secs_f64()
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+enum Decision {
+    Allowed { remaining: u32, reset: u64 },
+    Limited { retry_after: u64 },
+}
+
+pub struct RateLimitMiddleware {
+    key: RateLimitKey,
+    default_limit: Option<RateLimit>,
+    route_limits: Vec<(String, RateLimit)>,
+    buckets: Mutex<Buckets>,
+}
+
+struct Buckets {
+    by_key: HashMap<(String, String), Bucket>,
+    last_sweep: Instant,
+}
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+impl RateLimitMiddleware {
+    pub fn new(key: RateLimitKey) -> Self {
+        RateLimitMiddleware {
+            key,
+            default_limit: None,
+            route_limits: Vec::new(),
+            buckets: Mutex::new(Buckets {
+                by_key: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+        }
+    }
+
+    /// Limit for requests not covered by a route limit.
+    pub fn with_default_limit(mut self, limit: RateLimit) -> Self {
+        self.default_limit = Some(limit);
+        self
+    }
+
+    /// Limit for paths under `prefix`, counted separately from other routes.
+    pub fn with_route_limit(mut self, prefix: &str, limit: RateLimit) -> Self {
+        self.route_limits
+            .push((prefix.trim_end_matches('/').to_string(), limit));
+        self
+    }
+
+    fn limit_for(&self, path: &str) -> Option<(String, RateLimit)> {
+        self.route_limits
+            .iter()
+            .find(|(prefix, _)| match path.strip_prefix(prefix.as_str()) {
+                Some(rest) => rest.is_empty() || rest.starts_with('/'),
+                None => false,
+            })
+            .map(|(prefix, limit)| (prefix.clone(), *limit))
+            .or_else(|| self.default_limit.map(|limit| (String::new(), limit)))
+    }
+
+    fn client_key(&self, request: &HttpRequest) -> String {
+        let key = match &self.key {
+            RateLimitKey::ClientIp => request.client_ip.map(|ip| ip.to_string()),
+            RateLimitKey::Header(name) => request.get_header(name).cloned(),
+            RateLimitKey::Custom(f) => f(request),
+        };
+        // Clients without a key share one bucket rather than going unlimited
+        key.unwrap_or_else(|| "-".to_string())
+    }
+
+    fn take(&self, route: String, client: String, limit: RateLimit, now: Instant) -> Decision {
+        let mut buckets = self.buckets.lock().unwrap();
+        if now.duration_since(buckets.last_sweep) >= SWEEP_INTERVAL {
+            // A bucket idle for a whole period is full again, so dropping it is lossless
+            let max_idle = self
+                .route_limits
+                .iter()
+                .map(|(_, l)| l.period)
+                .chain(self.default_limit.map(|l| l.period))
+                .max()
+                .unwrap_or(SWEEP_INTERVAL);
+            buckets
+                .by_key
+                .retain(|_, b| now.duration_since(b.updated) < max_idle);
+            buckets.last_sweep = now;
+        }
+
+        let capacity = limit.requests.get() as f64;
+        let rate = limit.refill_per_sec();
+        let bucket = buckets.by_key.entry((route, client)).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Decision::Allowed {
+                remaining: bucket.tokens.floor() as u32,
+                reset: ((capacity - bucket.tokens) / rate).ceil() as u64,
+            }
+        } else {
+            Decision::Limited {
+                retry_after: ((1.0 - bucket.tokens) / rate).ceil().max(1.0) as u64,
+            }
+        }
+    }
+}
+
+impl HttpMiddleware for RateLimitMiddleware {
+    fn handle(
+        &self,
+        request: &mut HttpRequest,
+        next: &dyn Fn(&mut HttpRequest) -> HttpResponse,
+    ) -> HttpResponse {
+        let Some((route, limit)) = self.limit_for(&request.path) else {
+            return next(request);
+        };
+        let client = self.client_key(request);
+        let policy = format!("{};w={}", limit.requests, limit.period.as_secs());
+        match self.take(route, client, limit, Instant::now()) {
+            Decision::Allowed { remaining, reset } => next(request)
+                .with_header("RateLimit-Policy", &policy)
+                .with_header("RateLimit-Limit", &limit.requests.to_string())
+                .with_header("RateLimit-Remaining", &remaining.to_string())
+                .with_header("RateLimit-Reset", &reset.to_string()),
+            Decision::Limited { retry_after } => HttpError::new(HttpStatusCode::TooManyRequests, "Rate limit exceeded")
+                .into_response()
+                .with_header("Retry-After", &retry_after.to_string())
+                .with_header("RateLimit-Policy", &policy)
+                .with_header("RateLimit-Limit", &limit.requests.to_string())
+                .with_header("RateLimit-Remaining", "0")
+                .with_header("RateLimit-Reset", &retry_after.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(requests: u32) -> NonZeroU32 {
+        NonZeroU32::new(requests).unwrap()
+    }
+
+    #[test]
+    fn bucket_should_refill_over_time() {
+        let limiter = RateLimitMiddleware::new(RateLimitKey::ClientIp);
+        let limit = RateLimit::per_second(limit(2));
+        let start = Instant::now();
+        let take = |at: Duration| limiter.take("".into(), "1.2.3.4".into(), limit, start + at);
+
+        assert!(matches!(take(Duration::ZERO), Decision::Allowed { remaining: 1, .. }));
+        assert!(matches!(take(Duration::ZERO), Decision::Allowed { remaining: 0, .. }));
+        assert!(matches!(take(Duration::ZERO), Decision::Limited { retry_after: 1 }));
+        assert!(matches!(take(Duration::from_millis(500)), Decision::Allowed { .. }));
+        assert!(matches!(take(Duration::from_millis(500)), Decision::Limited { .. }));
+    }
+
+    #[test]
+    fn route_limits_should_take_precedence() {
+        let limiter = RateLimitMiddleware::new(RateLimitKey::ClientIp)
+            .with_default_limit(RateLimit::per_minute(limit(100)))
+            .with_route_limit("/delay", RateLimit::per_minute(limit(1)));
+        assert_eq!(limiter.limit_for("/delay").unwrap().1.requests.get(), 1);
+        assert_eq!(limiter.limit_for("/delayed").unwrap().1.requests.get(), 100);
+    }
+
+    #[test]
+    fn key_should_parse_from_spec() {
+        let mut reader = std::io::Cursor::new(&b"GET /delay?api_key=abc HTTP/1.1\r\nX-Api-Key: xyz\r\n\r\n"[..]);
+        let request = HttpRequest::from_reader(&mut reader).unwrap();
+        let key = |spec: &str| RateLimitMiddleware::new(spec.parse().unwrap()).client_key(&request);
+
+        assert_eq!(key("ip"), "-");
+        assert_eq!(key("header:X-Api-Key"), "xyz");
+        assert_eq!(key("query:api_key"), "abc");
+        assert!("header:".parse::<RateLimitKey>().is_err());
+        assert!("cookie:session".parse::<RateLimitKey>().is_err());
+    }
+
+    #[test]
+    fn stale_buckets_should_be_evicted() {
+        let limiter = RateLimitMiddleware::new(RateLimitKey::ClientIp)
+            .with_default_limit(RateLimit::per_second(limit(1)));
+        let start = Instant::now();
+        limiter.take("".into(), "a".into(), RateLimit::per_second(limit(1)), start);
+        limiter.take("".into(), "b".into(), RateLimit::per_second(limit(1)), start + SWEEP_INTERVAL);
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.by_key.len(), 1);
+    }
+}
@@ -0,0 +1,66 @@
+use crate::content_coding::{ContentCoding, SUPPORTED_CODINGS};
+use crate::http_error::HttpError;
+use crate::http_request::HttpRequest;
+use crate::http_response::{HttpResponse, HttpStatusCode, IntoResponse};
+use crate::middlewares::http_middleware::HttpMiddleware;
+
+pub struct RequestDecompressionMiddleware {
+    max_decoded_size: usize,
+}
+
+impl RequestDecompressionMiddleware {
+    pub fn new(max_decoded_size: usize) -> Self {
+        RequestDecompressionMiddleware { max_decoded_size }
+    }
+}
+
+impl HttpMiddleware for RequestDecompressionMiddleware {
+    fn handle(
+        &self,
+        request: &mut HttpRequest,
+        next: &dyn Fn(&mut HttpRequest) -> HttpResponse,
+    ) -> HttpResponse {
+        let Some(encoding) = request.get_header("Content-Encoding") else {
+            return next(request);
+        };
+        match ContentCoding::parse_list(encoding) {
+            Ok(codings) => {
+                request.content.set_decoding(codings, self.max_decoded_size);
+                // Both describe the encoded body, which inner middlewares never see
+                request.headers.retain(|name, _| {
+                    !name.eq_ignore_ascii_case("Content-Encoding") && !name.eq_ignore_ascii_case("Content-Length")
+                });
+                next(request)
+            }
+            Err(coding) => HttpError::new(
+                HttpStatusCode::UnsupportedMediaType,
+                &format!("Unsupported Content-Encoding: {}", coding),
+            )
+            .into_response()
+            .with_header("Accept-Encoding", SUPPORTED_CODINGS),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+
+    #[test]
+    fn decoded_request_should_drop_encoding_headers() {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(b"hello").unwrap();
+        let body = encoder.finish().unwrap();
+        let mut raw = format!("POST /files/a HTTP/1.1\r\ncontent-encoding: gzip\r\nContent-Length: {}\r\n\r\n", body.len()).into_bytes();
+        raw.extend_from_slice(&body);
+        let mut reader = Cursor::new(raw);
+        let mut request = HttpRequest::from_reader(&mut reader).unwrap();
+        let response = RequestDecompressionMiddleware::new(1024).handle(&mut request, &|req| {
+            assert!(req.get_header("Content-Encoding").is_none());
+            assert!(req.get_header("Content-Length").is_none());
+            HttpResponse::new(HttpStatusCode::OK).with_body(&req.content.to_string().unwrap())
+        });
+        assert_eq!(response.get_body().unwrap(), b"hello");
+    }
+}
@@ -0,0 +1,147 @@
+use crate::http_request::HttpRequest;
+use crate::http_response::HttpResponse;
+use crate::logger;
+use crate::middlewares::http_middleware::HttpMiddleware;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Correlation id of the current request, available to handlers and inner
+/// middlewares via `request.get_extension::<RequestId>()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestId(pub String);
+
+const MAX_LENGTH: usize = 128;
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// Shape of generated ids: `uuid` (v4) or `ulid`, which sorts by creation time.
+#[derive(EnumString, Debug, Clone, Copy, PartialEq)]
+pub enum RequestIdFormat {
+    #[strum(serialize = "uuid")]
+    Uuid,
+    #[strum(serialize = "ulid")]
+    Ulid,
+}
+
+impl RequestIdFormat {
+    fn generate(self) -> String {
+        match self {
+            RequestIdFormat::Uuid => uuid::Uuid::new_v4().to_string(),
+            RequestIdFormat::Ulid => {
+                let millis = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_millis())
+                    .unwrap_or(0);
+                let mut random = [0u8; 10];
+                getrandom::fill(&mut random).expect("No system randomness");
+                ulid(millis, random)
+            }
+        }
+    }
+}
+
+/// 48-bit millisecond timestamp followed by 80 random bits, as 26 Crockford base32
+/// characters.
+fn ulid(millis: u128, random: [u8; 10]) -> String {
+    let value = random
+        .iter()
+        .fold(millis & 0xFFFF_FFFF_FFFF, |acc, b| (acc << 8) | *b as u128);
+    (0..26)
+        .map(|i| CROCKFORD[((value >> (125 - 5 * i)) & 0x1F) as usize] as char)
+        .collect()
+}
+
+/// Reuses the client's request id header when it looks sane, otherwise generates a
+/// UUIDv4 or ULID, and echoes the id back on the response.
+pub struct RequestIdMiddleware {
+    header: String,
+    format: RequestIdFormat,
+}
+
+impl RequestIdMiddleware {
+    pub fn new() -> Self {
+        RequestIdMiddleware {
+            header: "X-Request-Id".to_string(),
+            format: RequestIdFormat::Uuid,
+        }
+    }
+
+    pub fn with_format(mut self, format: RequestIdFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn with_header(mut self, header: &str) -> Self {
+        self.header = header.to_string();
+        self
+    }
+}
+
+/// Ids end up in log lines and headers, so only short, unambiguous tokens are trusted.
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_LENGTH
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'))
+}
+
+impl HttpMiddleware for RequestIdMiddleware {
+    fn handle(
+        &self,
+        request: &mut HttpRequest,
+        next: &dyn Fn(&mut HttpRequest) -> HttpResponse,
+    ) -> HttpResponse {
+        let id = match request.get_header(&self.header) {
+            Some(id) if is_valid(id.trim()) => id.trim().to_string(),
+            _ => self.format.generate(),
+        };
+        logger::set_request_field("request_id", id.clone().into());
+        request.insert_extension(RequestId(id.clone()));
+        next(request).with_header(&self.header, &id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_response::HttpStatusCode;
+    use std::io::Cursor;
+
+    fn handle(raw: &str) -> (HttpResponse, Option<String>) {
+        let mut reader = Cursor::new(raw.as_bytes());
+        let mut request = HttpRequest::from_reader(&mut reader).unwrap();
+        let response = RequestIdMiddleware::new().handle(&mut request, &|_| HttpResponse::new(HttpStatusCode::OK));
+        (response, request.get_extension::<RequestId>().map(|r| r.0.clone()))
+    }
+
+    #[test]
+    fn incoming_id_should_be_reused_when_valid() {
+        let (response, id) = handle("GET / HTTP/1.1\r\nx-request-id: abc-123\r\n\r\n");
+        assert_eq!(id.as_deref(), Some("abc-123"));
+        assert_eq!(response.get_header("X-Request-Id").unwrap(), "abc-123");
+
+        let (response, id) = handle("GET / HTTP/1.1\r\nX-Request-Id: <script>\r\n\r\n");
+        let id = id.unwrap();
+        assert_eq!(id.len(), 36);
+        assert_eq!(response.get_header("X-Request-Id").unwrap(), &id);
+    }
+
+    #[test]
+    fn ids_should_be_validated() {
+        assert!(is_valid("01ARZ3NDEKTSV4RRFFQ69G5FAV"));
+        assert!(is_valid("req.42:a_b"));
+        assert!(!is_valid(""));
+        assert!(!is_valid("a b"));
+        assert!(!is_valid(&"x".repeat(MAX_LENGTH + 1)));
+    }
+
+    #[test]
+    fn ulid_should_be_crockford_encoded() {
+        // Example from the ULID spec's timestamp, with all random bits set
+        assert_eq!(ulid(1_469_918_176_385, [0xFF; 10]), "01ARYZ6S41ZZZZZZZZZZZZZZZZ");
+        assert_eq!(ulid(0, [0; 10]), "0".repeat(26));
+
+        let id = RequestIdFormat::Ulid.generate();
+        assert_eq!(id.len(), 26);
+        assert!(is_valid(&id));
+    }
+}
@@ -1,38 +1,112 @@
 use std::collections::HashMap;
 use super::super::http_request::{HttpRequest};
-use super::super::http_response::{HttpResponse, HttpStatusCode};
+use super::super::http_response::{HttpResponse, IntoResponse};
+use crate::http_error::HttpError;
 use crate::url_matcher::{UrlMatcher, MatchMethod};
 use crate::middlewares::http_middleware::HttpMiddleware;
-use crate::http_context::HttpContext;
+use crate::http_context::{HttpContext, StateMap};
+use std::any::TypeId;
+use std::sync::Arc;
+
+/// Request extension naming the route pattern that handled the request, e.g.
+/// `/echo/{message}`, for labelling without the raw path's cardinality.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchedRoute(pub String);
+
+/// A route handler with its arguments extracted and its return value converted.
+pub type BoxedHandler = Box<dyn Fn(&mut HttpRequest, &HttpContext) -> HttpResponse + Send + Sync>;
 
 pub struct RoutingMiddleware{
-    routes: HashMap<UrlMatcher, fn(&mut HttpRequest, &HttpContext) -> HttpResponse>,
+    routes: HashMap<UrlMatcher, BoxedHandler>,
+    states: Arc<StateMap>,
 }
 
 impl RoutingMiddleware {
     pub fn new() -> Self {
         RoutingMiddleware{
             routes: HashMap::new(),
+            states: Arc::new(StateMap::new()),
         }
     }
 
-    pub fn add_route(&mut self, method: MatchMethod, pattern: &str, handler: fn(&mut HttpRequest, &HttpContext) -> HttpResponse) {
+    /// Makes `state` available to handlers through the `State<T>` extractor.
+    pub fn add_state<T: Send + Sync + 'static>(&mut self, state: T) {
+        Arc::get_mut(&mut self.states)
+            .expect("State can't be added once the server is running")
+            .insert(TypeId::of::<T>(), Arc::new(state));
+    }
+
+    pub fn add_route(&mut self, method: MatchMethod, pattern: &str, handler: BoxedHandler) {
         let matcher = UrlMatcher::new(method, pattern);
         self.routes.insert(matcher, handler);
     }
+
+    /// Route for the request's own method first, so an explicit HEAD handler wins
+    /// over the GET one regardless of map order.
+    fn find_route(&self, request: &HttpRequest) -> Option<(&UrlMatcher, &BoxedHandler, HashMap<String, String>)> {
+        let methods = std::iter::once(request.method.clone()).chain(MatchMethod::fallback(&request.method));
+        for method in methods {
+            for (matcher, handler) in &self.routes {
+                let (matched, params) = matcher.match_url(&method, &request.path);
+                if matched {
+                    return Some((matcher, handler, params));
+                }
+            }
+        }
+        None
+    }
 }
 
 impl HttpMiddleware for RoutingMiddleware {
     fn handle(&self, request: &mut HttpRequest, _: &dyn Fn(&mut HttpRequest) -> HttpResponse) -> HttpResponse {
-        for (matcher, handler) in &self.routes {
-            let (matched, params) = matcher.match_url(&request.method, &request.path);
-            if matched {
-                let context = HttpContext::new_with_params(params);
-                return handler(request, &context);
-            }
+        if let Some((matcher, handler, params)) = self.find_route(request) {
+            request.insert_extension(MatchedRoute(matcher.pattern().to_string()));
+            let context = HttpContext::new_with_params(params).with_states(Arc::clone(&self.states));
+            return handler(request, &context);
         }
-        HttpResponse::new(HttpStatusCode::NotFound)
+        HttpError::not_found(&format!("No route for {}", request.path)).into_response()
         //Do not call next in routing middleware
         //next.handle(request)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_request::HttpMethod;
+    use crate::http_response::HttpStatusCode;
+    use std::io::Cursor;
+
+    fn handle(routing: &RoutingMiddleware, raw: &str) -> HttpResponse {
+        let mut reader = Cursor::new(raw.as_bytes());
+        let mut request = HttpRequest::from_reader(&mut reader).unwrap();
+        routing.handle(&mut request, &|_: &mut HttpRequest| unreachable!())
+    }
+
+    #[test]
+    fn head_should_prefer_explicit_handler_over_get() {
+        let mut routing = RoutingMiddleware::new();
+        routing.add_route(
+            MatchMethod::from_method(HttpMethod::GET),
+            "/users",
+            Box::new(|_: &mut HttpRequest, _: &HttpContext| HttpResponse::new(HttpStatusCode::OK)),
+        );
+        routing.add_route(
+            MatchMethod::from_method(HttpMethod::HEAD),
+            "/users",
+            Box::new(|_: &mut HttpRequest, _: &HttpContext| HttpResponse::new(HttpStatusCode::NoContent)),
+        );
+        routing.add_route(
+            MatchMethod::from_method(HttpMethod::GET),
+            "/posts",
+            Box::new(|_: &mut HttpRequest, _: &HttpContext| HttpResponse::new(HttpStatusCode::OK)),
+        );
+
+        let response = handle(&routing, "HEAD /users HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), HttpStatusCode::NoContent);
+        let response = handle(&routing, "HEAD /posts HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), HttpStatusCode::OK);
+        let response = handle(&routing, "POST /posts HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), HttpStatusCode::NotFound);
+    }
+}
@@ -1,11 +1,36 @@
+use crate::form::{self, MultipartLimits};
+use crate::http_error::HttpError;
 use crate::http_request::{HttpRequest, HttpMethod};
-use crate::http_response::HttpResponse;
+use crate::http_response::{HttpResponse, HttpStatusCode, IntoResponse};
 use crate::middlewares::http_middleware::HttpMiddleware;
+use crate::middlewares::routing_middleware::MatchedRoute;
 use crate::url_matcher::{MatchMethod, UrlMatcher};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Keeps concurrent uploads of the same file from sharing a staging file.
+static STAGING_ID: AtomicU64 = AtomicU64::new(0);
 
 pub struct StaticFilesMiddleware {
     base_path: String,
     matcher: UrlMatcher,
+    upload_limits: MultipartLimits,
+}
+
+/// The last component of a client supplied file name, or `None` if nothing usable is left.
+fn sanitize_filename(filename: &str) -> Option<&str> {
+    let name = filename.rsplit(['/', '\\']).next()?.trim();
+    (!name.is_empty() && name != "." && name != "..").then_some(name)
+}
+
+fn upload_error(e: std::io::Error) -> HttpError {
+    match e.kind() {
+        std::io::ErrorKind::FileTooLarge => HttpError::new(HttpStatusCode::ContentTooLarge, &e.to_string()),
+        std::io::ErrorKind::InvalidData | std::io::ErrorKind::UnexpectedEof => {
+            HttpError::bad_request(&format!("Malformed upload: {}", e))
+        }
+        _ => HttpError::new(HttpStatusCode::InternalServerError, "Failed to store upload"),
+    }
 }
 
 impl StaticFilesMiddleware {
@@ -14,7 +39,84 @@ impl StaticFilesMiddleware {
         StaticFilesMiddleware {
             base_path: base_path.to_string(),
             matcher: UrlMatcher::new(MatchMethod::ANY, &pattern),
+            upload_limits: MultipartLimits::default(),
+        }
+    }
+
+    /// Size limits for `multipart/form-data` uploads.
+    pub fn with_upload_limits(mut self, limits: MultipartLimits) -> Self {
+        self.upload_limits = limits;
+        self
+    }
+
+    /// Saves the file parts of a browser form upload. Posting to a directory keeps
+    /// each file's own name; posting to a file path stores the first file there.
+    /// Files only replace existing ones once the whole body has been read, so a
+    /// failed upload leaves nothing behind.
+    fn save_uploads(&self, request: &mut HttpRequest, boundary: &str, target: &str) -> Result<Vec<String>, HttpError> {
+        let mut staged = Vec::new();
+        let result = self
+            .stage_uploads(request, boundary, target, &mut staged)
+            .and_then(|_| {
+                staged
+                    .iter()
+                    .try_for_each(|(staging, path)| std::fs::rename(staging, path))
+                    .map_err(upload_error)
+            });
+        if let Err(error) = result {
+            for (staging, _) in &staged {
+                let _ = std::fs::remove_file(staging);
+            }
+            return Err(error);
+        }
+        Ok(staged
+            .iter()
+            .map(|(_, path)| path.file_name().unwrap_or_default().to_string_lossy().to_string())
+            .collect())
+    }
+
+    /// Writes each file part to a hidden staging file next to its target, recording
+    /// `(staging, target)` pairs as it goes.
+    fn stage_uploads(
+        &self,
+        request: &mut HttpRequest,
+        boundary: &str,
+        target: &str,
+        staged: &mut Vec<(PathBuf, PathBuf)>,
+    ) -> Result<(), HttpError> {
+        let into_directory = target.ends_with('/') || Path::new(target).is_dir();
+        let mut multipart = request
+            .content
+            .multipart(boundary, self.upload_limits)
+            .map_err(upload_error)?;
+        while let Some(part) = multipart.next_part().map_err(upload_error)? {
+            let Some(filename) = part.filename.as_deref() else {
+                continue;
+            };
+            let path = if into_directory {
+                let name = sanitize_filename(filename)
+                    .ok_or_else(|| HttpError::bad_request(&format!("Invalid file name: {}", filename)))?;
+                Path::new(target).join(name)
+            } else if staged.is_empty() {
+                PathBuf::from(target)
+            } else {
+                continue;
+            };
+            if staged.iter().any(|(_, p)| *p == path) {
+                return Err(HttpError::bad_request(&format!("Duplicate file name: {}", filename)));
+            }
+            let staging = path.with_file_name(format!(
+                ".{}.{}.upload",
+                path.file_name().unwrap_or_default().to_string_lossy(),
+                STAGING_ID.fetch_add(1, Ordering::Relaxed)
+            ));
+            multipart.save_to(&staging).map_err(upload_error)?;
+            staged.push((staging, path));
         }
+        if staged.is_empty() {
+            return Err(HttpError::bad_request("No file parts in upload"));
+        }
+        Ok(())
     }
 }
 
@@ -28,13 +130,14 @@ impl HttpMiddleware for StaticFilesMiddleware {
         if !is_matched {
             return next(request);
         }
+        request.insert_extension(MatchedRoute(self.matcher.pattern().to_string()));
         let file_path = format!(
             "{}/{}",
             self.base_path,
             params.get("file_path").unwrap_or(&"".to_string())
         );
         match request.method {
-            HttpMethod::GET => {
+            HttpMethod::GET | HttpMethod::HEAD => {
                 if let Ok(contents) = std::fs::read_to_string(&file_path) {
                     HttpResponse::new(crate::http_response::HttpStatusCode::OK).with_body(&contents)
                     .with_header("Content-Type", "application/octet-stream")
@@ -43,10 +146,113 @@ impl HttpMiddleware for StaticFilesMiddleware {
                 }
             }
             HttpMethod::POST => {
-                std::fs::write(&file_path, &request.content.to_string().unwrap()).unwrap_or(());
-                HttpResponse::new(crate::http_response::HttpStatusCode::Created)
+                match request.get_header("Content-Type").and_then(|t| form::boundary(t)) {
+                    Some(boundary) => self
+                        .save_uploads(request, &boundary, &file_path)
+                        .map(|saved| HttpResponse::new(HttpStatusCode::Created).with_body(&saved.join("\n")))
+                        .into_response(),
+                    None => request
+                        .content
+                        .to_bytes()
+                        .and_then(|body| std::fs::write(&file_path, body))
+                        .map(|_| HttpResponse::new(HttpStatusCode::Created))
+                        .map_err(upload_error)
+                        .into_response(),
+                }
             }
-            _ => return next(request),
+            _ => next(request),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn upload(middleware: &StaticFilesMiddleware, path: &str, body: &str) -> HttpResponse {
+        let raw = format!(
+            "POST {} HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=----form\r\nContent-Length: {}\r\n\r\n{}",
+            path,
+            body.len(),
+            body
+        );
+        let mut reader = Cursor::new(raw.as_bytes());
+        let mut request = HttpRequest::from_reader(&mut reader).unwrap();
+        middleware.handle(&mut request, &|_| HttpResponse::new(HttpStatusCode::NotFound))
+    }
+
+    #[test]
+    fn browser_uploads_should_be_saved() {
+        let dir = std::env::temp_dir().join(format!("static-files-upload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let middleware = StaticFilesMiddleware::new("/files", dir.to_str().unwrap());
+        let body = "------form\r\nContent-Disposition: form-data; name=\"note\"\r\n\r\nskip me\r\n\
+                    ------form\r\nContent-Disposition: form-data; name=\"file\"; filename=\"../../notes.txt\"\r\nContent-Type: text/plain\r\n\r\nhello\r\n\
+                    ------form--\r\n";
+
+        let response = upload(&middleware, "/files/", body);
+        assert_eq!(response.status_code(), HttpStatusCode::Created);
+        assert_eq!(response.get_body().unwrap(), b"notes.txt");
+        assert_eq!(std::fs::read_to_string(dir.join("notes.txt")).unwrap(), "hello");
+
+        let response = upload(&middleware, "/files/renamed", body);
+        assert_eq!(response.status_code(), HttpStatusCode::Created);
+        assert_eq!(std::fs::read_to_string(dir.join("renamed")).unwrap(), "hello");
+
+        let limited = StaticFilesMiddleware::new("/files", dir.to_str().unwrap()).with_upload_limits(MultipartLimits {
+            max_part_size: 3,
+            max_total_size: 1024,
+        });
+        assert_eq!(upload(&limited, "/files/big", body).status_code(), HttpStatusCode::ContentTooLarge);
+        assert!(!dir.join("big").exists());
+        assert_eq!(upload(&middleware, "/files/x", "------form--\r\n").status_code(), HttpStatusCode::BadRequest);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_uploads_should_leave_no_files() {
+        let dir = std::env::temp_dir().join(format!("static-files-rollback-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.txt"), "old").unwrap();
+        let middleware = StaticFilesMiddleware::new("/files", dir.to_str().unwrap());
+        let file = |name: &str, content: &str| {
+            format!("------form\r\nContent-Disposition: form-data; name=\"f\"; filename=\"{}\"\r\n\r\n{}\r\n", name, content)
+        };
+
+        // Truncated after the first file
+        let body = format!("{}{}", file("a.txt", "new"), file("b.txt", "cut"));
+        assert_eq!(upload(&middleware, "/files/", &body).status_code(), HttpStatusCode::BadRequest);
+        let body = format!("{}{}------form--\r\n", file("a.txt", "new"), file("a.txt", "again"));
+        assert_eq!(upload(&middleware, "/files/", &body).status_code(), HttpStatusCode::BadRequest);
+
+        let mut names: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["a.txt"]);
+        assert_eq!(std::fs::read_to_string(dir.join("a.txt")).unwrap(), "old");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn plain_upload_should_keep_bytes_and_report_failures() {
+        let dir = std::env::temp_dir().join(format!("static-files-plain-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let middleware = StaticFilesMiddleware::new("/files", dir.to_str().unwrap());
+        let post = |path: &str, body: &[u8]| {
+            let mut raw = format!("POST {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n", path, body.len()).into_bytes();
+            raw.extend_from_slice(body);
+            let mut reader = Cursor::new(raw);
+            let mut request = HttpRequest::from_reader(&mut reader).unwrap();
+            middleware.handle(&mut request, &|_| HttpResponse::new(HttpStatusCode::NotFound))
+        };
+
+        assert_eq!(post("/files/blob", b"\xff\x00\xfe").status_code(), HttpStatusCode::Created);
+        assert_eq!(std::fs::read(dir.join("blob")).unwrap(), b"\xff\x00\xfe");
+        let response = post("/files/missing/blob", b"abc");
+        assert_eq!(response.status_code(), HttpStatusCode::InternalServerError);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,59 +1,357 @@
-use crate::http_request::HttpRequest;
-use crate::http_response::HttpResponse;
+use crate::http_error::HttpError;
+use crate::http_request::{HttpMethod, HttpRequest};
+use crate::http_response::{HttpResponse, HttpStatusCode, IntoResponse};
+use crate::middlewares::Principal;
 use crate::middlewares::http_middleware::HttpMiddleware;
-use std::collections::HashMap;
-use std::sync::Mutex;
+use crate::middlewares::MatchedRoute;
+use crate::panics::CaughtPanic;
+use crate::timestamp;
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
+/// Rolling windows reported next to the lifetime totals, in seconds.
+const WINDOWS: [(&str, u64); 3] = [("1m", 60), ("5m", 300), ("15m", 900)];
+/// Latency samples kept per route for percentiles.
+const ROUTE_SAMPLES: usize = 1024;
+
+/// Counts every request passing through. It belongs outside `PanicMiddleware` so
+/// caught panics are counted, while the numbers are served by the
+/// [`StatisticEndpointMiddleware`] from [`StatisticMiddleware::endpoint`], which can
+/// sit behind auth.
 pub struct StatisticMiddleware {
+    shared: Arc<Shared>,
+}
+
+/// Serves the numbers collected by a [`StatisticMiddleware`] at `path` and resets
+/// them on `POST {path}/reset`.
+pub struct StatisticEndpointMiddleware {
     path: String,
+    reset_path: String,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    started_at: SystemTime,
+    started: Instant,
     statistic: Mutex<Statistic>,
 }
 
+#[derive(Clone, Copy, Default)]
+struct Slot {
+    second: u64,
+    requests: u64,
+    errors: u64,
+}
+
+#[derive(Default)]
+struct RouteLatency {
+    count: u64,
+    samples: VecDeque<Duration>,
+}
+
 struct Statistic{
     total_requests: u64,
-    requests_by_path: HashMap<String, u64>,
+    panics: u64,
     response_statuses: HashMap<u16, u64>,
+    // One slot per second of the longest window, reused round-robin
+    slots: Vec<Slot>,
+    routes: HashMap<String, RouteLatency>,
+}
+
+impl Statistic {
+    fn new() -> Self {
+        Statistic {
+            total_requests: 0,
+            panics: 0,
+            response_statuses: HashMap::new(),
+            slots: vec![Slot::default(); WINDOWS[WINDOWS.len() - 1].1 as usize],
+            routes: HashMap::new(),
+        }
+    }
+
+    fn record(&mut self, second: u64, route_key: String, status: u16, elapsed: Duration) {
+        self.total_requests += 1;
+        *self.response_statuses.entry(status).or_insert(0) += 1;
+
+        let len = self.slots.len();
+        let slot = &mut self.slots[(second % len as u64) as usize];
+        if slot.second != second {
+            *slot = Slot { second, ..Default::default() };
+        }
+        slot.requests += 1;
+        if status >= 500 {
+            slot.errors += 1;
+        }
+
+        // Keyed by route pattern only, so clients can't grow the map with paths
+        let route = self.routes.entry(route_key).or_default();
+        route.count += 1;
+        if route.samples.len() == ROUTE_SAMPLES {
+            route.samples.pop_front();
+        }
+        route.samples.push_back(elapsed);
+    }
+
+    /// Requests and 5xx responses in the `seconds` ending with `now`.
+    fn window(&self, now: u64, seconds: u64) -> (u64, u64) {
+        self.slots
+            .iter()
+            .filter(|s| s.requests > 0 && s.second <= now && now - s.second < seconds)
+            .fold((0, 0), |(requests, errors), s| (requests + s.requests, errors + s.errors))
+    }
+}
+
+/// Nearest-rank percentile of sorted samples, in milliseconds.
+fn percentile(sorted: &[Duration], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil().max(1.0) as usize;
+    sorted[rank - 1].as_secs_f64() * 1000.0
+}
+
+fn wants_json(request: &HttpRequest) -> bool {
+    request.query_params.get("format").map(|f| f == "json").unwrap_or(false)
+        || request
+            .get_header("Accept")
+            .map(|a| a.split(',').any(|t| t.split(';').next().unwrap_or("").trim() == "application/json"))
+            .unwrap_or(false)
 }
 
 impl StatisticMiddleware {
-    pub fn new(path: &str) -> Self {
+    pub fn new() -> Self {
         StatisticMiddleware {
-            path: path.to_string(),
-            statistic: Mutex::new(Statistic {
-                total_requests: 0,
-                requests_by_path: HashMap::new(),
-                response_statuses: HashMap::new(),
+            shared: Arc::new(Shared {
+                started_at: SystemTime::now(),
+                started: Instant::now(),
+                statistic: Mutex::new(Statistic::new()),
             }),
         }
     }
+
+    pub fn endpoint(&self, path: &str) -> StatisticEndpointMiddleware {
+        StatisticEndpointMiddleware {
+            path: path.to_string(),
+            reset_path: format!("{}/reset", path.trim_end_matches('/')),
+            shared: self.shared.clone(),
+        }
+    }
 }
 
-impl HttpMiddleware for StatisticMiddleware {
+impl StatisticEndpointMiddleware {
+
+    fn requests_by_path(statistic: &Statistic) -> BTreeMap<&str, u64> {
+        statistic.routes.iter().map(|(key, route)| (key.as_str(), route.count)).collect()
+    }
+
+    fn to_json(&self, statistic: &Statistic) -> Value {
+        let now = self.shared.started.elapsed().as_secs();
+        let windows: Map<String, Value> = WINDOWS
+            .iter()
+            .map(|(name, seconds)| {
+                let (requests, errors) = statistic.window(now, *seconds);
+                (name.to_string(), json!({ "requests": requests, "errors": errors }))
+            })
+            .collect();
+        let mut route_keys: Vec<&String> = statistic.routes.keys().collect();
+        route_keys.sort();
+        let routes: Map<String, Value> = route_keys
+            .into_iter()
+            .map(|key| {
+                let route = &statistic.routes[key];
+                let mut sorted: Vec<Duration> = route.samples.iter().copied().collect();
+                sorted.sort();
+                let latency = json!({
+                    "count": route.count,
+                    "p50_ms": percentile(&sorted, 50.0),
+                    "p95_ms": percentile(&sorted, 95.0),
+                    "p99_ms": percentile(&sorted, 99.0),
+                });
+                (key.clone(), latency)
+            })
+            .collect();
+        let statuses: Map<String, Value> = statistic
+            .response_statuses
+            .iter()
+            .map(|(status, count)| (status.to_string(), json!(count)))
+            .collect();
+        json!({
+            "started_at": timestamp::rfc3339(self.shared.started_at),
+            "uptime_seconds": now,
+            "total_requests": statistic.total_requests,
+            "panics": statistic.panics,
+            "requests_by_path": Self::requests_by_path(statistic),
+            "response_statuses": statuses,
+            "windows": windows,
+            "routes": routes,
+        })
+    }
+
+    fn to_text(&self, statistic: &Statistic) -> String {
+        let now = self.shared.started.elapsed().as_secs();
+        let mut body = format!(
+            "Started At: {}\nUptime: {}s\nTotal Requests: {}\nPanics: {}\nRequests by Path: {:?}\nResponse Statuses: {:?}\n",
+            timestamp::rfc3339(self.shared.started_at),
+            now,
+            statistic.total_requests,
+            statistic.panics,
+            Self::requests_by_path(statistic),
+            statistic.response_statuses
+        );
+        for (name, seconds) in WINDOWS {
+            let (requests, errors) = statistic.window(now, seconds);
+            body.push_str(&format!("Last {}: {} requests, {} errors\n", name, requests, errors));
+        }
+        let mut route_keys: Vec<&String> = statistic.routes.keys().collect();
+        route_keys.sort();
+        for key in route_keys {
+            let mut sorted: Vec<Duration> = statistic.routes[key].samples.iter().copied().collect();
+            sorted.sort();
+            body.push_str(&format!(
+                "{}: p50 {:.3}ms, p95 {:.3}ms, p99 {:.3}ms\n",
+                key,
+                percentile(&sorted, 50.0),
+                percentile(&sorted, 95.0),
+                percentile(&sorted, 99.0)
+            ));
+        }
+        body
+    }
+}
+
+impl HttpMiddleware for StatisticEndpointMiddleware {
     fn handle(
         &self,
         request: &mut HttpRequest,
         next: &dyn Fn(&mut HttpRequest) -> HttpResponse,
     ) -> HttpResponse {
+        if request.path == self.reset_path {
+            request.insert_extension(MatchedRoute(self.reset_path.clone()));
+            if request.method != HttpMethod::POST {
+                return HttpError::new(HttpStatusCode::MethodNotAllowed, "Statistics are reset with POST")
+                    .into_response()
+                    .with_header("Allow", "POST");
+            }
+            // Set by AuthMiddleware; without it anyone could wipe the numbers
+            if request.get_extension::<Principal>().is_none() {
+                return HttpError::new(HttpStatusCode::Forbidden, "Resetting statistics requires authentication")
+                    .into_response();
+            }
+            *self.shared.statistic.lock().unwrap() = Statistic::new();
+            return HttpResponse::new(HttpStatusCode::NoContent);
+        }
         if request.path == self.path {
-            let statistic = self.statistic.lock().unwrap();
-            let body = format!(
-                "Total Requests: {}\nRequests by Path: {:?}\nResponse Statuses: {:?}",
-                statistic.total_requests, statistic.requests_by_path, statistic.response_statuses
-            );
-            HttpResponse::new(crate::http_response::HttpStatusCode::OK).with_body(&body)
-        } else {
-            let mut statistic = self.statistic.lock().unwrap();
-
-            statistic.total_requests += 1;
-
-            *statistic.requests_by_path
-                .entry(format!("{} {}", request.method, request.path))
-                .or_insert(0) += 1;
-            let response = next(request);
-            *statistic.response_statuses
-                .entry(response.status_code() as u16)
-                .or_insert(0) += 1;
-            return response;
+            request.insert_extension(MatchedRoute(self.path.clone()));
+            let statistic = self.shared.statistic.lock().unwrap();
+            if wants_json(request) {
+                return HttpResponse::new(HttpStatusCode::OK)
+                    .with_body(&self.to_json(&statistic).to_string())
+                    .with_header("Content-Type", "application/json");
+            }
+            return HttpResponse::new(HttpStatusCode::OK).with_body(&self.to_text(&statistic));
+        }
+        next(request)
+    }
+}
+
+impl HttpMiddleware for StatisticMiddleware {
+    fn handle(
+        &self,
+        request: &mut HttpRequest,
+        next: &dyn Fn(&mut HttpRequest) -> HttpResponse,
+    ) -> HttpResponse {
+        let timer = Instant::now();
+        let response = next(request);
+        let elapsed = timer.elapsed();
+        let route = request
+            .get_extension::<MatchedRoute>()
+            .map(|r| r.0.as_str())
+            .unwrap_or("unmatched");
+        let method = match &request.method {
+            HttpMethod::Extension(_) => "OTHER".to_string(),
+            method => method.to_string(),
+        };
+        let mut statistic = self.shared.statistic.lock().unwrap();
+        if request.get_extension::<CaughtPanic>().is_some() {
+            statistic.panics += 1;
+        }
+        statistic.record(
+            self.shared.started.elapsed().as_secs(),
+            format!("{} {}", method, route),
+            response.status_code().as_u16(),
+            elapsed,
+        );
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn percentile_should_use_nearest_rank() {
+        let samples: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&samples, 50.0), 50.0);
+        assert_eq!(percentile(&samples, 99.0), 99.0);
+        assert_eq!(percentile(&samples[..1], 95.0), 1.0);
+        assert_eq!(percentile(&[], 50.0), 0.0);
+    }
+
+    #[test]
+    fn windows_should_only_count_recent_requests() {
+        let mut statistic = Statistic::new();
+        let ms = Duration::from_millis(1);
+        statistic.record(10, "GET /a".to_string(), 200, ms);
+        statistic.record(500, "GET /a".to_string(), 500, ms);
+        statistic.record(890, "GET /a".to_string(), 200, ms);
+        assert_eq!(statistic.window(900, 60), (1, 0));
+        assert_eq!(statistic.window(900, 900), (3, 1));
+        // Second 910 reuses second 10's slot
+        statistic.record(910, "GET /a".to_string(), 200, ms);
+        assert_eq!(statistic.window(910, 900), (3, 1));
+        assert_eq!(statistic.total_requests, 4);
+    }
+
+    #[test]
+    fn json_should_be_served_on_request() {
+        let stats = StatisticMiddleware::new().endpoint("/stats");
+        let mut reader = Cursor::new("GET /stats?format=json HTTP/1.1\r\n\r\n".as_bytes());
+        let mut request = HttpRequest::from_reader(&mut reader).unwrap();
+        let response = stats.handle(&mut request, &|_| HttpResponse::new(HttpStatusCode::OK));
+        let body: Value = serde_json::from_slice(response.get_body().unwrap()).unwrap();
+        assert_eq!(body["total_requests"], 0);
+        assert!(body["windows"]["15m"]["requests"].is_u64());
+
+        let mut reader = Cursor::new("POST /stats/reset HTTP/1.1\r\n\r\n".as_bytes());
+        let mut request = HttpRequest::from_reader(&mut reader).unwrap();
+        let response = stats.handle(&mut request, &|_| HttpResponse::new(HttpStatusCode::OK));
+        assert_eq!(response.status_code(), HttpStatusCode::Forbidden);
+    }
+
+    #[test]
+    fn requests_should_be_keyed_by_route() {
+        let stats = StatisticMiddleware::new();
+        for raw in [
+            "GET /echo/a HTTP/1.1\r\n\r\n",
+            "GET /echo/b HTTP/1.1\r\n\r\n",
+            "GET /nowhere/1 HTTP/1.1\r\n\r\n",
+            "BREW /nowhere/2 HTTP/1.1\r\n\r\n",
+        ] {
+            let mut reader = Cursor::new(raw.as_bytes());
+            let mut request = HttpRequest::from_reader(&mut reader).unwrap();
+            stats.handle(&mut request, &|req| {
+                if req.path.starts_with("/echo/") {
+                    req.insert_extension(MatchedRoute("/echo/{message}".to_string()));
+                }
+                HttpResponse::new(HttpStatusCode::OK)
+            });
         }
+        let statistic = stats.shared.statistic.lock().unwrap();
+        let keys: Vec<&str> = StatisticEndpointMiddleware::requests_by_path(&statistic).into_keys().collect();
+        assert_eq!(keys, ["GET /echo/{message}", "GET unmatched", "OTHER unmatched"]);
     }
 }
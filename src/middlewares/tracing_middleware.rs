@@ -0,0 +1,64 @@
+use crate::http_request::HttpRequest;
+use crate::http_response::HttpResponse;
+use crate::logger;
+use crate::middlewares::http_middleware::HttpMiddleware;
+use crate::middlewares::MatchedRoute;
+use crate::trace::{Span, SpanContext, SpanKind};
+
+/// Continues the caller's W3C trace, or starts one, with a server span per request.
+/// Handlers find the span's context via `request.get_extension::<SpanContext>()`
+/// and can time their own work with `Span::child`.
+pub struct TracingMiddleware;
+
+impl TracingMiddleware {
+    pub fn new() -> Self {
+        TracingMiddleware
+    }
+}
+
+impl HttpMiddleware for TracingMiddleware {
+    fn handle(
+        &self,
+        request: &mut HttpRequest,
+        next: &dyn Fn(&mut HttpRequest) -> HttpResponse,
+    ) -> HttpResponse {
+        let parent = request
+            .get_header("traceparent")
+            .and_then(|h| SpanContext::from_traceparent(h));
+        let (context, parent_span_id) = match parent {
+            Some(parent) => {
+                let parent = match request.get_header("tracestate") {
+                    Some(state) => parent.with_trace_state(state),
+                    None => parent,
+                };
+                (parent.child(), Some(parent.span_id))
+            }
+            None => (SpanContext::new_root(), None),
+        };
+        logger::set_request_field("trace_id", context.trace_id_hex().into());
+        request.insert_extension(context.clone());
+
+        let mut span = Span::start(
+            &request.method.to_string(),
+            SpanKind::Server,
+            context,
+            parent_span_id,
+        );
+        let response = next(request);
+
+        let status = response.status_code();
+        span.set_attribute("http.request.method", request.method.to_string());
+        span.set_attribute("url.path", request.path.clone());
+        span.set_attribute("http.response.status_code", status.as_u16());
+        span.set_attribute("http.server.duration_ms", span.elapsed().as_secs_f64() * 1000.0);
+        if let Some(route) = request.get_extension::<MatchedRoute>() {
+            span.set_name(&format!("{} {}", request.method, route.0));
+            span.set_attribute("http.route", route.0.clone());
+        }
+        if status.is_server_error() {
+            span.set_error();
+        }
+        // Lets clients correlate the response with the trace (Trace Context Level 2)
+        response.with_header("traceresponse", &span.context().traceparent())
+    }
+}
@@ -0,0 +1,108 @@
+use crate::logger::log;
+use std::any::Any;
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::cell::{Cell, RefCell};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Once;
+
+/// A panic caught by [`catch`], identified by an id that is safe to show clients.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaughtPanic {
+    pub error_id: String,
+    pub message: String,
+    pub location: Option<String>,
+    /// Only captured when enabled through `RUST_BACKTRACE` or `RUST_LIB_BACKTRACE`.
+    pub backtrace: Option<String>,
+}
+
+impl CaughtPanic {
+    pub fn log(&self, message: &str) {
+        log!(
+            Error,
+            message,
+            error_id = self.error_id,
+            panic = self.message,
+            location = self.location,
+            backtrace = self.backtrace
+        );
+    }
+}
+
+struct PanicDetails {
+    location: Option<String>,
+    backtrace: Option<String>,
+}
+
+thread_local! {
+    static CATCHING: Cell<usize> = const { Cell::new(0) };
+    static LAST_PANIC: RefCell<Option<PanicDetails>> = const { RefCell::new(None) };
+}
+
+static INSTALL_HOOK: Once = Once::new();
+
+/// The location and backtrace are only available to the panic hook, so they are
+/// stashed for `catch`. Panics inside `catch` are reported by its caller alone;
+/// others still reach the previous hook.
+fn install_hook() {
+    INSTALL_HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if CATCHING.with(|c| c.get()) == 0 {
+                previous(info);
+                return;
+            }
+            let backtrace = Backtrace::capture();
+            let details = PanicDetails {
+                location: info.location().map(|l| l.to_string()),
+                backtrace: (backtrace.status() == BacktraceStatus::Captured)
+                    .then(|| backtrace.to_string()),
+            };
+            LAST_PANIC.with(|p| *p.borrow_mut() = Some(details));
+        }));
+    });
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Box<dyn Any>".to_string()
+    }
+}
+
+/// Runs `f`, turning a panic into a [`CaughtPanic`] instead of unwinding further.
+pub fn catch<R>(f: impl FnOnce() -> R) -> Result<R, CaughtPanic> {
+    install_hook();
+    CATCHING.with(|c| c.set(c.get() + 1));
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    CATCHING.with(|c| c.set(c.get() - 1));
+    result.map_err(|payload| {
+        let details = LAST_PANIC.with(|p| p.borrow_mut().take());
+        CaughtPanic {
+            error_id: uuid::Uuid::new_v4().simple().to_string(),
+            message: panic_message(payload.as_ref()),
+            location: details.as_ref().and_then(|d| d.location.clone()),
+            backtrace: details.and_then(|d| d.backtrace),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catch_should_capture_message_and_location() {
+        assert_eq!(catch(|| 42), Ok(42));
+
+        let caught = catch(|| panic!("boom {}", 7)).unwrap_err();
+        assert_eq!(caught.message, "boom 7");
+        assert!(caught.location.unwrap().starts_with("src/panics.rs:"));
+        assert_eq!(caught.error_id.len(), 32);
+
+        let nested = catch(|| catch(|| panic!("inner")).unwrap_err().message);
+        assert_eq!(nested, Ok("inner".to_string()));
+    }
+}
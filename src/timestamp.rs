@@ -0,0 +1,86 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+struct UtcDateTime {
+    year: i64,
+    month: u32,
+    day: u32,
+    hour: u64,
+    minute: u64,
+    second: u64,
+    micros: u32,
+}
+
+/// Splits a time into UTC calendar fields (Howard Hinnant's `civil_from_days`).
+fn to_utc(time: SystemTime) -> UtcDateTime {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let days = (secs / 86_400) as i64;
+    let secs_of_day = secs % 86_400;
+
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    UtcDateTime {
+        year,
+        month,
+        day,
+        hour: secs_of_day / 3_600,
+        minute: secs_of_day % 3_600 / 60,
+        second: secs_of_day % 60,
+        micros: since_epoch.subsec_micros(),
+    }
+}
+
+/// `10/Oct/2000:13:55:36 +0000`, as used by the Common Log Format.
+pub fn clf(time: SystemTime) -> String {
+    let t = to_utc(time);
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        t.day,
+        MONTHS[(t.month - 1) as usize],
+        t.year,
+        t.hour,
+        t.minute,
+        t.second
+    )
+}
+
+/// `2000-10-10T13:55:36.000000Z`, RFC 3339 in UTC with microseconds.
+pub fn rfc3339(time: SystemTime) -> String {
+    let t = to_utc(time);
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        t.year, t.month, t.day, t.hour, t.minute, t.second, t.micros
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn should_format_known_instants() {
+        let time = UNIX_EPOCH + Duration::from_secs(971_186_136);
+        assert_eq!(clf(time), "10/Oct/2000:13:55:36 +0000");
+
+        let leap_day = UNIX_EPOCH + Duration::from_secs(1_709_164_800);
+        assert_eq!(clf(leap_day), "29/Feb/2024:00:00:00 +0000");
+        assert_eq!(clf(UNIX_EPOCH), "01/Jan/1970:00:00:00 +0000");
+        assert_eq!(
+            rfc3339(time + Duration::from_micros(42)),
+            "2000-10-10T13:55:36.000042Z"
+        );
+    }
+}
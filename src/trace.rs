@@ -0,0 +1,460 @@
+use crate::log_writer::{LogSink, LogWriter};
+use crate::logger::log;
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::OnceLock;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender, sync_channel};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const SAMPLED: u8 = 0x01;
+const SERVICE_NAME: &str = "codecrafters-http-server";
+
+/// Identifies a span within a trace, as carried by `traceparent`/`tracestate`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpanContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub flags: u8,
+    pub trace_state: Option<String>,
+}
+
+fn random<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    // All-zero ids are invalid, so keep drawing in the (unlikely) event of one
+    while bytes.iter().all(|b| *b == 0) {
+        getrandom::fill(&mut bytes).expect("No system randomness");
+    }
+    bytes
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    if hex.len() != N * 2 || !hex.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b)) {
+        return None;
+    }
+    let mut bytes = [0u8; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+impl SpanContext {
+    /// A new sampled trace with a fresh root span.
+    pub fn new_root() -> Self {
+        SpanContext {
+            trace_id: random(),
+            span_id: random(),
+            flags: SAMPLED,
+            trace_state: None,
+        }
+    }
+
+    /// The same trace and flags with a new span id.
+    pub fn child(&self) -> Self {
+        SpanContext {
+            span_id: random(),
+            ..self.clone()
+        }
+    }
+
+    /// Parses `version-traceid-parentid-flags`. Unknown future versions are accepted
+    /// as long as they start with the version 00 fields.
+    pub fn from_traceparent(header: &str) -> Option<Self> {
+        let header = header.trim();
+        let mut parts = header.split('-');
+        let version = from_hex::<1>(parts.next()?)?[0];
+        let trace_id = from_hex::<16>(parts.next()?)?;
+        let span_id = from_hex::<8>(parts.next()?)?;
+        let flags = from_hex::<1>(parts.next()?)?[0];
+        let valid_length = if version == 0 { header.len() == 55 } else { header.as_bytes().get(55).is_none_or(|b| *b == b'-') };
+        if version == 0xff || !valid_length || trace_id == [0; 16] || span_id == [0; 8] {
+            return None;
+        }
+        Some(SpanContext {
+            trace_id,
+            span_id,
+            flags,
+            trace_state: None,
+        })
+    }
+
+    /// Keeps `tracestate` only if it has at most 32 well-formed `key=value` members.
+    pub fn with_trace_state(mut self, header: &str) -> Self {
+        let members: Vec<&str> = header.split(',').map(str::trim).filter(|m| !m.is_empty()).collect();
+        let valid = members.len() <= 32
+            && members.iter().all(|m| {
+                m.split_once('=')
+                    .is_some_and(|(k, v)| !k.is_empty() && !v.is_empty() && !k.contains(' '))
+            });
+        if valid && !members.is_empty() {
+            self.trace_state = Some(members.join(","));
+        }
+        self
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-{:02x}", to_hex(&self.trace_id), to_hex(&self.span_id), self.flags)
+    }
+
+    pub fn trace_id_hex(&self) -> String {
+        to_hex(&self.trace_id)
+    }
+
+    pub fn is_sampled(&self) -> bool {
+        self.flags & SAMPLED != 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpanKind {
+    Internal = 1,
+    Server = 2,
+}
+
+thread_local! {
+    // Innermost open span on this thread; new spans become its children
+    static CURRENT: RefCell<Vec<SpanContext>> = const { RefCell::new(Vec::new()) };
+}
+
+/// An open span, ended and exported when dropped.
+pub struct Span {
+    context: SpanContext,
+    parent_span_id: Option<[u8; 8]>,
+    name: String,
+    kind: SpanKind,
+    start: SystemTime,
+    timer: Instant,
+    attributes: Vec<(String, Value)>,
+    error: bool,
+}
+
+impl Span {
+    /// Starts a span that is current on this thread until dropped.
+    pub fn start(name: &str, kind: SpanKind, context: SpanContext, parent_span_id: Option<[u8; 8]>) -> Self {
+        CURRENT.with(|c| c.borrow_mut().push(context.clone()));
+        Span {
+            context,
+            parent_span_id,
+            name: name.to_string(),
+            kind,
+            start: SystemTime::now(),
+            timer: Instant::now(),
+            attributes: Vec::new(),
+            error: false,
+        }
+    }
+
+    /// A child of the current span, or a new root if there is none.
+    pub fn child(name: &str) -> Self {
+        match CURRENT.with(|c| c.borrow().last().cloned()) {
+            Some(parent) => Span::start(name, SpanKind::Internal, parent.child(), Some(parent.span_id)),
+            None => Span::start(name, SpanKind::Internal, SpanContext::new_root(), None),
+        }
+    }
+
+    pub fn context(&self) -> &SpanContext {
+        &self.context
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.timer.elapsed()
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        self.name = name.to_string();
+    }
+
+    pub fn set_attribute(&mut self, key: &str, value: impl Into<Value>) {
+        self.attributes.push((key.to_string(), value.into()));
+    }
+
+    pub fn set_error(&mut self) {
+        self.error = true;
+    }
+
+    /// The span in OTLP/JSON form.
+    fn to_otlp(&self, elapsed: Duration) -> Value {
+        let nanos = |t: SystemTime| t.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        let attributes: Vec<Value> = self
+            .attributes
+            .iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    Value::Number(n) if n.is_i64() || n.is_u64() => json!({ "intValue": n.to_string() }),
+                    Value::Number(n) => json!({ "doubleValue": n }),
+                    Value::Bool(b) => json!({ "boolValue": b }),
+                    Value::String(s) => json!({ "stringValue": s }),
+                    other => json!({ "stringValue": other.to_string() }),
+                };
+                json!({ "key": key, "value": value })
+            })
+            .collect();
+        let mut span = json!({
+            "traceId": to_hex(&self.context.trace_id),
+            "spanId": to_hex(&self.context.span_id),
+            "name": self.name,
+            "kind": self.kind as u8,
+            "startTimeUnixNano": nanos(self.start).to_string(),
+            "endTimeUnixNano": nanos(self.start + elapsed).to_string(),
+            "attributes": attributes,
+            "status": { "code": if self.error { 2 } else { 0 } },
+        });
+        if let Some(parent) = self.parent_span_id {
+            span["parentSpanId"] = Value::from(to_hex(&parent));
+        }
+        if let Some(state) = &self.context.trace_state {
+            span["traceState"] = Value::from(state.as_str());
+        }
+        span
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        CURRENT.with(|c| {
+            let mut stack = c.borrow_mut();
+            if let Some(index) = stack.iter().rposition(|s| s.span_id == self.context.span_id) {
+                stack.remove(index);
+            }
+        });
+        if self.context.is_sampled()
+            && let Some(exporter) = EXPORTER.get()
+        {
+            exporter.export(self.to_otlp(self.timer.elapsed()));
+        }
+    }
+}
+
+/// Where finished spans are sent.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SpanExport {
+    /// An OTLP/HTTP collector accepting JSON, e.g. `http://localhost:4318/v1/traces`.
+    Otlp(String),
+    /// One OTLP/JSON span per line appended to this file.
+    File(String),
+}
+
+enum Exporter {
+    Lines(LogWriter),
+    Otlp(SyncSender<Value>),
+}
+
+impl Exporter {
+    fn export(&self, span: Value) {
+        match self {
+            Exporter::Lines(writer) => writer.write_line(span.to_string()),
+            // Spans are dropped rather than slowing requests when the collector lags
+            Exporter::Otlp(sender) => {
+                let _ = sender.try_send(span);
+            }
+        }
+    }
+}
+
+static EXPORTER: OnceLock<Exporter> = OnceLock::new();
+
+/// Starts exporting sampled spans. Without it spans are still propagated but dropped.
+pub fn init(export: SpanExport) -> std::io::Result<()> {
+    let exporter = match export {
+        SpanExport::File(path) => Exporter::Lines(LogWriter::new(LogSink::File(path), "trace")?),
+        SpanExport::Otlp(url) => {
+            let endpoint = OtlpEndpoint::parse(&url)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+            let (sender, receiver) = sync_channel(QUEUE_SIZE);
+            thread::Builder::new()
+                .name("trace-exporter".to_string())
+                .spawn(move || export_loop(receiver, endpoint))?;
+            Exporter::Otlp(sender)
+        }
+    };
+    let _ = EXPORTER.set(exporter);
+    Ok(())
+}
+
+const QUEUE_SIZE: usize = 4096;
+const BATCH_SIZE: usize = 512;
+const BATCH_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq)]
+struct OtlpEndpoint {
+    host: String,
+    path: String,
+}
+
+impl OtlpEndpoint {
+    /// Plain `http://host[:port][/path]`; the path defaults to `/v1/traces`.
+    fn parse(url: &str) -> Result<Self, String> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("Only http:// OTLP endpoints are supported: {}", url))?;
+        let (host, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/v1/traces"),
+        };
+        if host.is_empty() {
+            return Err(format!("Missing host in {}", url));
+        }
+        let host = if host.contains(':') { host.to_string() } else { format!("{}:80", host) };
+        Ok(OtlpEndpoint {
+            host,
+            path: path.to_string(),
+        })
+    }
+
+    fn post(&self, body: &str) -> std::io::Result<u16> {
+        let mut stream = TcpStream::connect(&self.host)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            self.host,
+            body.len(),
+            body
+        )?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        response
+            .split(' ')
+            .nth(1)
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Malformed collector response"))
+    }
+}
+
+fn export_request(spans: Vec<Value>) -> Value {
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{ "key": "service.name", "value": { "stringValue": SERVICE_NAME } }]
+            },
+            "scopeSpans": [{ "scope": { "name": SERVICE_NAME }, "spans": spans }]
+        }]
+    })
+}
+
+fn export_loop(receiver: Receiver<Value>, endpoint: OtlpEndpoint) {
+    // Block for the first span, then gather more for up to BATCH_DELAY
+    while let Ok(first) = receiver.recv() {
+        let mut spans = vec![first];
+        let deadline = Instant::now() + BATCH_DELAY;
+        while spans.len() < BATCH_SIZE {
+            match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(span) => spans.push(span),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        let count = spans.len();
+        match endpoint.post(&export_request(spans).to_string()) {
+            Ok(status) if (200..300).contains(&status) => {}
+            Ok(status) => log!(Warn, "Trace collector rejected spans", status = status, spans = count),
+            Err(e) => log!(Warn, "Failed to export spans", error = e.to_string(), spans = count),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufRead;
+    use std::net::TcpListener;
+
+    #[test]
+    fn traceparent_should_round_trip() {
+        let header = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let context = SpanContext::from_traceparent(header).unwrap();
+        assert!(context.is_sampled());
+        assert_eq!(context.traceparent(), header);
+        assert_eq!(context.child().trace_id, context.trace_id);
+
+        assert!(SpanContext::from_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_none());
+        assert!(SpanContext::from_traceparent("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").is_none());
+        assert!(SpanContext::from_traceparent("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01").is_none());
+        assert!(SpanContext::from_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-x").is_none());
+        assert!(SpanContext::from_traceparent("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-x").is_some());
+    }
+
+    #[test]
+    fn trace_state_should_be_validated() {
+        let context = SpanContext::new_root().with_trace_state("congo=t61rcWkgMzE, rojo=00f067aa0ba902b7");
+        assert_eq!(context.trace_state.as_deref(), Some("congo=t61rcWkgMzE,rojo=00f067aa0ba902b7"));
+        assert_eq!(SpanContext::new_root().with_trace_state("garbage").trace_state, None);
+    }
+
+    #[test]
+    fn child_spans_should_nest_under_the_current_span() {
+        let root = Span::start("root", SpanKind::Server, SpanContext::new_root(), None);
+        let child = Span::child("child");
+        let grandchild = Span::child("grandchild");
+        assert_eq!(child.parent_span_id, Some(root.context.span_id));
+        assert_eq!(grandchild.parent_span_id, Some(child.context.span_id));
+        assert_eq!(grandchild.context.trace_id, root.context.trace_id);
+        drop(grandchild);
+        drop(child);
+        let sibling = Span::child("sibling");
+        assert_eq!(sibling.parent_span_id, Some(root.context.span_id));
+
+        let otlp = sibling.to_otlp(Duration::from_millis(2));
+        assert_eq!(otlp["kind"], 1);
+        assert_eq!(otlp["parentSpanId"], to_hex(&root.context.span_id));
+    }
+
+    #[test]
+    fn otlp_export_should_post_to_collector() {
+        let collector = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/v1/traces", collector.local_addr().unwrap());
+        let endpoint = OtlpEndpoint::parse(&url).unwrap();
+        let stub = thread::spawn(move || {
+            let (stream, _) = collector.accept().unwrap();
+            let mut reader = std::io::BufReader::new(&stream);
+            let mut head = String::new();
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some(len) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                    content_length = len.trim().parse().unwrap();
+                }
+                if line == "\r\n" {
+                    break;
+                }
+                head.push_str(&line);
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            (&stream).write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+            (head, String::from_utf8(body).unwrap())
+        });
+
+        let mut span = Span::start("GET /hello", SpanKind::Server, SpanContext::new_root(), None);
+        span.set_attribute("http.response.status_code", 200);
+        let trace_id = span.context().trace_id_hex();
+        let request = export_request(vec![span.to_otlp(Duration::from_millis(1))]);
+        assert_eq!(endpoint.post(&request.to_string()).unwrap(), 200);
+
+        let (head, body) = stub.join().unwrap();
+        assert!(head.starts_with("POST /v1/traces HTTP/1.1"));
+        let body: Value = serde_json::from_str(&body).unwrap();
+        let exported = &body["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(exported["traceId"], trace_id);
+        assert_eq!(exported["kind"], 2);
+        assert_eq!(exported["attributes"][0]["value"]["intValue"], "200");
+    }
+
+    #[test]
+    fn endpoint_should_require_http() {
+        assert_eq!(
+            OtlpEndpoint::parse("http://localhost:4318").unwrap(),
+            OtlpEndpoint { host: "localhost:4318".to_string(), path: "/v1/traces".to_string() }
+        );
+        assert!(OtlpEndpoint::parse("https://collector").is_err());
+    }
+}
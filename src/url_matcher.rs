@@ -9,6 +9,7 @@ pub struct UrlMatcher {
 }
 
 #[derive(Hash, Eq, PartialEq, Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum MatchMethod {
     ANY,
     CONCRET(HttpMethod),
@@ -24,15 +25,28 @@ impl MatchMethod {
             MatchMethod::CONCRET(m) => m == method,
         }
     }
+
+    /// Method whose routes serve `method` when none is registered for it: HEAD is
+    /// served by the GET route, and the body is dropped when writing.
+    pub fn fallback(method: &HttpMethod) -> Option<HttpMethod> {
+        match method {
+            HttpMethod::HEAD => Some(HttpMethod::GET),
+            _ => None,
+        }
+    }
 }
 
 impl UrlMatcher {
     pub fn new(method: MatchMethod, pattern: &str) -> Self {
         let re = Regex::new("^((\\/\\{[\\w\\-\\.]+\\})?(\\/[\\w\\-\\.]+)?)+(\\/\\{[\\w\\-]+\\*?\\})?\\/?$").unwrap();
-        if re.is_match(pattern) == false {
+        if !re.is_match(pattern) {
             panic!("Invalid URL pattern: {}", pattern);
         }
-        UrlMatcher{pattern: pattern.to_string(), method: method}
+        UrlMatcher{pattern: pattern.to_string(), method}
+    }
+
+    pub fn pattern(&self) -> &str {
+        &self.pattern
     }
 
     pub fn match_url(&self, method: &HttpMethod, url: &str) -> (bool, HashMap<String, String>) {
@@ -48,8 +62,7 @@ impl UrlMatcher {
             let pattern_part = pattern_parts[i];
             if pattern_part.starts_with('{') && pattern_part.ends_with('}') {
                 let param_name = &pattern_part[1..pattern_part.len()-1];
-                if param_name.ends_with('*') {
-                    let param_name = &param_name[..param_name.len()-1];
+                if let Some(param_name) = param_name.strip_suffix('*') {
                     let remaining_url = url_parts[i..].join("/");
                     params.insert(param_name.to_string(), remaining_url);
                     return (true, params);
@@ -114,6 +127,14 @@ mod tests {
         assert_eq!(params.get("wildcard").unwrap(), "123/posts/456");
     }
 
+    #[test]
+    fn match_url_should_only_match_the_exact_method() {
+        let matcher = UrlMatcher::new(MatchMethod::from_method(HttpMethod::GET), "/users");
+        assert!(!matcher.match_url(&HttpMethod::HEAD, "/users").0);
+        assert_eq!(MatchMethod::fallback(&HttpMethod::HEAD), Some(HttpMethod::GET));
+        assert_eq!(MatchMethod::fallback(&HttpMethod::POST), None);
+    }
+
     #[test]
     fn match_url_should_work_ending_slash() {
         let matcher = UrlMatcher::new(MatchMethod::from_method(HttpMethod::GET), "/users/");